@@ -0,0 +1,207 @@
+use std::collections::BTreeSet;
+
+use bytes::Bytes;
+
+use crate::violations::Violation;
+
+/// Most pieces a torrent may have. A Have beyond it is out of range for any torrent, which
+/// also bounds the Haves buffered before the piece count is known.
+pub const MAX_PIECES: usize = 1 << 21;
+
+/// Which pieces a peer has. Until the torrent's piece count is known the peer's Bitfield and
+/// Haves are buffered as received; once it is known they are validated against it.
+#[derive(Debug)]
+pub enum PeerPieces {
+    Pending {
+        bitfield: Option<Bytes>,
+        haves: BTreeSet<u32>,
+    },
+    Known(Vec<bool>),
+}
+impl Default for PeerPieces {
+    fn default() -> Self {
+        PeerPieces::Pending {
+            bitfield: None,
+            haves: BTreeSet::new(),
+        }
+    }
+}
+impl PeerPieces {
+    pub fn bitfield(&mut self, payload: Bytes, piece_count: Option<usize>) -> anyhow::Result<()> {
+        match piece_count {
+            Some(piece_count) => *self = PeerPieces::Known(decode_bitfield(&payload, piece_count)?),
+            None => {
+                *self = PeerPieces::Pending {
+                    bitfield: Some(payload),
+                    haves: BTreeSet::new(),
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn have(&mut self, index: u32, piece_count: Option<usize>) -> anyhow::Result<()> {
+        if let Some(piece_count) = piece_count {
+            self.resolve(piece_count)?;
+        }
+        match self {
+            PeerPieces::Pending { .. } if index as usize >= MAX_PIECES => {
+                let detail = format!("Have for piece {}, past any torrent's last", index);
+                return Err(Violation::HaveOutOfRange.error(detail));
+            }
+            PeerPieces::Pending { haves, .. } => {
+                haves.insert(index);
+            }
+            PeerPieces::Known(bits) => match bits.get_mut(index as usize) {
+                Some(bit) => *bit = true,
+                None => {
+                    let detail = format!("Have for piece {} of {}", index, bits.len());
+                    return Err(Violation::HaveOutOfRange.error(detail));
+                }
+            },
+        }
+        Ok(())
+    }
+    /// The peer's pieces, once the piece count is known.
+    pub fn known(&self) -> Option<&[bool]> {
+        match self {
+            PeerPieces::Known(bits) => Some(bits),
+            PeerPieces::Pending { .. } => None,
+        }
+    }
+    /// Fraction of pieces the peer has, once the piece count is known.
+    pub fn progress(&self) -> Option<f64> {
+        match self {
+            PeerPieces::Known(bits) if !bits.is_empty() => {
+                Some(bits.iter().filter(|bit| **bit).count() as f64 / bits.len() as f64)
+            }
+            _ => None,
+        }
+    }
+    /// Applies buffered state now that the piece count is known.
+    pub fn resolve(&mut self, piece_count: usize) -> anyhow::Result<()> {
+        let PeerPieces::Pending { bitfield, haves } = self else {
+            return Ok(());
+        };
+        let mut bits = match bitfield {
+            Some(payload) => decode_bitfield(payload, piece_count)?,
+            None => vec![false; piece_count],
+        };
+        for index in haves.iter() {
+            match bits.get_mut(*index as usize) {
+                Some(bit) => *bit = true,
+                None => {
+                    let detail = format!("Have for piece {} of {}", index, piece_count);
+                    return Err(Violation::HaveOutOfRange.error(detail));
+                }
+            }
+        }
+        *self = PeerPieces::Known(bits);
+        Ok(())
+    }
+}
+
+/// Decodes a Bitfield payload for a torrent with `piece_count` pieces. The payload must be
+/// exactly `ceil(piece_count / 8)` bytes and the spare bits in the last byte must be clear.
+pub fn decode_bitfield(payload: &[u8], piece_count: usize) -> anyhow::Result<Vec<bool>> {
+    let expected = piece_count.div_ceil(8);
+    if payload.len() != expected {
+        let detail = format!(
+            "Bitfield of {} bytes for {} pieces, expected {}",
+            payload.len(),
+            piece_count,
+            expected
+        );
+        return Err(Violation::BitfieldLength.error(detail));
+    }
+    let mask = 0b10000000;
+    let mut bits = Vec::with_capacity(expected * 8);
+    for byte in payload {
+        for i in 0..8 {
+            bits.push(byte & (mask >> i) > 0);
+        }
+    }
+    if bits[piece_count..].iter().any(|bit| *bit) {
+        let detail = format!("Bitfield has spare bits set past piece {}", piece_count);
+        return Err(Violation::BitfieldSpareBits.error(detail));
+    }
+    bits.truncate(piece_count);
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECES: usize = 13;
+
+    fn has(pieces: &PeerPieces, index: usize) -> bool {
+        match pieces {
+            PeerPieces::Known(bits) => bits.get(index).copied().unwrap_or(false),
+            PeerPieces::Pending { .. } => false,
+        }
+    }
+
+    #[test]
+    fn test_decode_bitfield_non_multiple_of_eight() {
+        let bits = decode_bitfield(&[0b1000_0000, 0b0000_1000], PIECES).unwrap();
+        assert_eq!(bits.len(), PIECES);
+        assert!(bits[0] && bits[12]);
+        assert_eq!(bits.iter().filter(|bit| **bit).count(), 2);
+
+        assert!(decode_bitfield(&[0xff], PIECES).is_err());
+        assert!(decode_bitfield(&[0xff, 0xf8, 0x00], PIECES).is_err());
+        assert!(decode_bitfield(&[0xff, 0xfc], PIECES).is_err());
+        assert!(decode_bitfield(&[0xff, 0xf8], PIECES).is_ok());
+    }
+
+    #[test]
+    fn test_state_buffered_until_piece_count_known() {
+        let mut early_bitfield = PeerPieces::default();
+        early_bitfield
+            .bitfield(Bytes::from_static(&[0x00, 0x08]), None)
+            .unwrap();
+        early_bitfield.have(3, None).unwrap();
+        assert!(!has(&early_bitfield, 12));
+
+        let mut only_haves = PeerPieces::default();
+        only_haves.have(5, None).unwrap();
+
+        early_bitfield.resolve(PIECES).unwrap();
+        only_haves.resolve(PIECES).unwrap();
+        assert!(has(&early_bitfield, 3) && has(&early_bitfield, 12));
+        assert!(!has(&early_bitfield, 13));
+        assert!(has(&only_haves, 5) && !has(&only_haves, 3));
+
+        // A Have after sizing is bounds checked against the real piece count.
+        assert!(only_haves.have(12, Some(PIECES)).is_ok());
+        assert!(only_haves.have(13, Some(PIECES)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_buffered_state_rejected_on_resolve() {
+        let mut padded = PeerPieces::default();
+        padded
+            .bitfield(Bytes::from_static(&[0xff, 0xff]), None)
+            .unwrap();
+        assert!(padded.resolve(PIECES).is_err());
+
+        let mut short = PeerPieces::default();
+        short.bitfield(Bytes::from_static(&[0xff]), None).unwrap();
+        assert!(short.resolve(PIECES).is_err());
+
+        let mut out_of_range = PeerPieces::default();
+        out_of_range.have(20, None).unwrap();
+        assert!(out_of_range.resolve(PIECES).is_err());
+    }
+
+    #[test]
+    fn test_buffered_haves_are_bounded() {
+        let mut pieces = PeerPieces::default();
+        for _ in 0..1000 {
+            pieces.have(7, None).unwrap();
+        }
+        assert!(matches!(&pieces, PeerPieces::Pending { haves, .. } if haves.len() == 1));
+        assert!(pieces.have(MAX_PIECES as u32 - 1, None).is_ok());
+        assert!(pieces.have(MAX_PIECES as u32, None).is_err());
+    }
+}
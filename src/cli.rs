@@ -0,0 +1,230 @@
+use std::path::PathBuf;
+
+use url::Url;
+
+use crate::{peer_trace::TraceConfig, peer_view::PeerSort};
+
+pub enum Command {
+    /// Download the torrent behind a magnet link. Falls back to the built-in sample link.
+    Download {
+        link: Option<String>,
+        /// Trackers to use in addition to the magnet's own (`--add-tracker`, repeatable).
+        add_trackers: Vec<Url>,
+        trace: Option<TraceConfig>,
+        /// Disconnect peers on every protocol deviation, including tolerated ones.
+        strict: bool,
+        /// Where to write the per-peer violation report in strict mode.
+        report: Option<PathBuf>,
+        /// Check peer state machine invariants in release builds too (`--check-invariants`).
+        check_invariants: bool,
+        /// Show a refreshing peer table sorted this way instead of the unchoked count.
+        peers_view: Option<PeerSort>,
+        /// Size of the buffer UDP announce responses are read into (`--tracker-buffer`).
+        tracker_buffer: Option<usize>,
+        /// File that tracker quality is kept in between sessions (`--tracker-history`).
+        tracker_history: Option<PathBuf>,
+    },
+    /// Print what the magnet link describes; with `swarm`, also scrape its trackers.
+    Info { link: String, swarm: bool },
+}
+/// An announce response header, and the largest UDP payload.
+const MIN_TRACKER_BUFFER: usize = 20;
+const MAX_TRACKER_BUFFER: usize = 65507;
+
+impl Command {
+    pub fn parse(args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = args.peekable();
+        if args.peek().map(String::as_str) == Some("info") {
+            args.next();
+            let mut link = None;
+            let mut swarm = false;
+            for arg in args {
+                match arg.as_str() {
+                    "--swarm" => swarm = true,
+                    flag if flag.starts_with("--") => anyhow::bail!("Unknown flag {}", flag),
+                    _ if link.is_none() => link = Some(arg),
+                    _ => anyhow::bail!("Unexpected argument {}", arg),
+                }
+            }
+            let link =
+                link.ok_or_else(|| anyhow::anyhow!("Usage: magdl info <magnet> [--swarm]"))?;
+            return Ok(Command::Info { link, swarm });
+        }
+
+        let mut link = None;
+        let mut add_trackers = Vec::new();
+        let mut trace_dir = None;
+        let mut full_pieces = false;
+        let mut strict = false;
+        let mut report = None;
+        let mut check_invariants = false;
+        let mut peers_view = false;
+        let mut sort_peers = None;
+        let mut tracker_buffer = None;
+        let mut tracker_history = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--trace-peers" => {
+                    let dir = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--trace-peers needs a directory"))?;
+                    trace_dir = Some(PathBuf::from(dir));
+                }
+                "--add-tracker" => {
+                    let url = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--add-tracker needs a tracker URL"))?;
+                    add_trackers.push(
+                        Url::parse(&url)
+                            .map_err(|e| anyhow::anyhow!("Invalid tracker URL {}: {}", url, e))?,
+                    );
+                }
+                "--trace-full-pieces" => full_pieces = true,
+                "--strict" => strict = true,
+                "--report" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--report needs a file path"))?;
+                    report = Some(PathBuf::from(path));
+                }
+                "--check-invariants" => check_invariants = true,
+                "--peers-view" => peers_view = true,
+                "--sort-peers" => {
+                    let sort = args.next().ok_or_else(|| {
+                        anyhow::anyhow!("--sort-peers needs rate, progress or age")
+                    })?;
+                    sort_peers = Some(sort.parse::<PeerSort>()?);
+                }
+                "--tracker-buffer" => {
+                    let bytes = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--tracker-buffer needs a size in bytes"))?;
+                    let bytes = bytes
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|bytes| (MIN_TRACKER_BUFFER..=MAX_TRACKER_BUFFER).contains(bytes))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "--tracker-buffer must be {} to {} bytes, got {}",
+                                MIN_TRACKER_BUFFER,
+                                MAX_TRACKER_BUFFER,
+                                bytes
+                            )
+                        })?;
+                    tracker_buffer = Some(bytes);
+                }
+                "--tracker-history" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--tracker-history needs a file path"))?;
+                    tracker_history = Some(PathBuf::from(path));
+                }
+                flag if flag.starts_with("--") => anyhow::bail!("Unknown flag {}", flag),
+                _ if link.is_none() => link = Some(arg),
+                _ => anyhow::bail!("Unexpected argument {}", arg),
+            }
+        }
+        if sort_peers.is_some() && !peers_view {
+            anyhow::bail!("--sort-peers only applies to --peers-view");
+        }
+        if report.is_some() && !strict {
+            anyhow::bail!("--report is only written in --strict mode");
+        }
+        let trace = trace_dir.map(|dir| TraceConfig { dir, full_pieces });
+        Ok(Command::Download {
+            link,
+            add_trackers,
+            trace,
+            strict,
+            report,
+            check_invariants,
+            peers_view: peers_view.then(|| sort_peers.unwrap_or(PeerSort::Rate)),
+            tracker_buffer,
+            tracker_history,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Command> {
+        Command::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert!(matches!(
+            parse(&[]),
+            Ok(Command::Download {
+                link: None,
+                trace: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(&["magnet:?xt=a"]),
+            Ok(Command::Download { link: Some(link), .. }) if link == "magnet:?xt=a"
+        ));
+        assert!(matches!(
+            parse(&["magnet:?xt=a", "--trace-peers", "traces"]),
+            Ok(Command::Download { trace: Some(TraceConfig { dir, full_pieces: false }), .. })
+                if dir.as_path() == std::path::Path::new("traces")
+        ));
+        assert!(parse(&["magnet:?xt=a", "--trace-peers"]).is_err());
+        assert!(matches!(
+            parse(&[
+                "magnet:?xt=a",
+                "--add-tracker",
+                "udp://one.example:6969/announce",
+                "--add-tracker",
+                "udp://two.example:1337",
+            ]),
+            Ok(Command::Download { add_trackers, .. }) if add_trackers.len() == 2
+        ));
+        assert!(parse(&["magnet:?xt=a", "--add-tracker", "not a url"]).is_err());
+        assert!(matches!(
+            parse(&["magnet:?xt=a", "--strict", "--report", "violations.json"]),
+            Ok(Command::Download { strict: true, report: Some(path), .. })
+                if path.as_path() == std::path::Path::new("violations.json")
+        ));
+        assert!(parse(&["magnet:?xt=a", "--report", "violations.json"]).is_err());
+        assert!(matches!(
+            parse(&["--check-invariants"]),
+            Ok(Command::Download { check_invariants: true, .. })
+        ));
+        assert!(matches!(
+            parse(&["magnet:?xt=a", "--peers-view"]),
+            Ok(Command::Download { peers_view: Some(PeerSort::Rate), .. })
+        ));
+        assert!(matches!(
+            parse(&["--peers-view", "--sort-peers", "progress"]),
+            Ok(Command::Download { peers_view: Some(PeerSort::Progress), .. })
+        ));
+        assert!(parse(&["--peers-view", "--sort-peers", "name"]).is_err());
+        assert!(parse(&["--sort-peers", "age"]).is_err());
+        assert!(matches!(
+            parse(&["--tracker-buffer", "32768"]),
+            Ok(Command::Download { tracker_buffer: Some(32768), .. })
+        ));
+        assert!(parse(&["--tracker-buffer", "8"]).is_err());
+        assert!(parse(&["--tracker-buffer", "lots"]).is_err());
+        assert!(matches!(
+            parse(&["--tracker-history", "trackers.txt"]),
+            Ok(Command::Download { tracker_history: Some(path), .. })
+                if path.as_path() == std::path::Path::new("trackers.txt")
+        ));
+        assert!(parse(&["--tracker-history"]).is_err());
+        assert!(matches!(
+            parse(&["info", "magnet:?xt=a", "--swarm"]),
+            Ok(Command::Info { link, swarm: true }) if link == "magnet:?xt=a"
+        ));
+        assert!(matches!(
+            parse(&["info", "magnet:?xt=a"]),
+            Ok(Command::Info { swarm: false, .. })
+        ));
+        assert!(parse(&["info"]).is_err());
+        assert!(parse(&["info", "magnet:?xt=a", "--bogus"]).is_err());
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+/// Every this many dials, the oldest address nothing is known about is dialed regardless of
+/// score, so that scored addresses cannot starve new ones.
+pub const EXPLORATION_INTERVAL: usize = 4;
+/// A connect faster than this marks the peer's subnet as fast.
+pub const FAST_CONNECT: Duration = Duration::from_millis(250);
+/// An address is no longer retried after this many failed dials.
+pub const MAX_DIAL_FAILURES: u32 = 5;
+/// Dial history of an address not dialed for this long is forgotten.
+pub const HISTORY_EXPIRY: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DialHistory {
+    pub successes: u32,
+    pub failures: u32,
+}
+
+/// Scores a candidate address; higher is dialed first. Returns `None` when nothing is known
+/// about it, which orders it like a score of zero but makes it eligible for exploration.
+///
+/// `source_quality` is in `0.0..=1.0` and describes where the address came from.
+pub fn dial_score(source_quality: f64, history: DialHistory, fast_subnet: bool) -> Option<f64> {
+    if source_quality <= 0.0 && history == DialHistory::default() && !fast_subnet {
+        return None;
+    }
+    let mut score = source_quality;
+    score += history.successes.min(3) as f64;
+    score -= history.failures.min(3) as f64;
+    if fast_subnet {
+        score += 0.5;
+    }
+    Some(score)
+}
+
+/// The /16 of an IPv4 address or the /32 of an IPv6 address.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, 0, 0))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], 0, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source_quality: f64,
+    /// Order of queueing, so ties and exploration go to the oldest address.
+    seq: u64,
+    /// Set for retries that are not due yet.
+    not_before: Option<Instant>,
+    /// Score the candidate is ranked by, once due.
+    score: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HistoryEntry {
+    history: DialHistory,
+    updated: Instant,
+}
+
+/// Score as a sortable integer.
+fn score_key(score: Option<f64>) -> i64 {
+    (score.unwrap_or(0.0) * 1000.0) as i64
+}
+
+/// Addresses waiting to be dialed, handed out best first.
+#[derive(Debug, Default)]
+pub struct DialQueue {
+    pending: HashMap<SocketAddr, Candidate>,
+    /// Due candidates, best first.
+    ranked: BTreeMap<(Reverse<i64>, u64), SocketAddr>,
+    /// Due candidates nothing is known about, oldest first.
+    unknown: BTreeMap<u64, SocketAddr>,
+    /// Retries not due yet, earliest first.
+    waiting: BTreeSet<(Instant, u64, SocketAddr)>,
+    next_seq: u64,
+    history: HashMap<SocketAddr, HistoryEntry>,
+    /// When history was last checked for expired entries.
+    history_expired: Option<Instant>,
+    fast_subnets: HashSet<IpAddr>,
+    dialed: usize,
+    /// Addresses handed out by `pop` whose dial or connection has not finished yet, with the
+    /// source quality they were queued with.
+    active: HashMap<SocketAddr, f64>,
+    /// Number of active addresses on each IP.
+    active_ips: HashMap<IpAddr, usize>,
+    /// Nothing is handed out before this instant.
+    paused_until: Option<Instant>,
+}
+impl DialQueue {
+    /// Queues an address unless it is already waiting or being dialed. Returns whether it was
+    /// added; an address already waiting keeps the better of the two source qualities.
+    pub fn push(&mut self, addr: SocketAddr, source_quality: f64) -> bool {
+        if self.active.contains_key(&addr) {
+            return false;
+        }
+        if let Some(candidate) = self.pending.get_mut(&addr) {
+            candidate.source_quality = candidate.source_quality.max(source_quality);
+            self.rescore(addr);
+            return false;
+        }
+        self.insert(addr, source_quality, None);
+        true
+    }
+    /// The best address that is due at `now`, marked active until `finish` is called for it.
+    /// Addresses on an IP that is already active wait, whatever their port.
+    pub fn pop(&mut self, now: Instant) -> Option<SocketAddr> {
+        match self.paused_until {
+            Some(until) if now < until => return None,
+            Some(_) => self.paused_until = None,
+            None => {}
+        }
+        while let Some(&(at, _, addr)) = self.waiting.first() {
+            if at > now {
+                break;
+            }
+            self.waiting.pop_first();
+            self.pending
+                .get_mut(&addr)
+                .expect("waiting address is pending")
+                .not_before = None;
+            self.rank(addr);
+        }
+        let idle = |addr: &SocketAddr| !self.active_ips.contains_key(&addr.ip());
+        let best = *self.ranked.values().find(|a| idle(a))?;
+        self.dialed += 1;
+        let explore = self.dialed.is_multiple_of(EXPLORATION_INTERVAL);
+        let addr = match self.unknown.values().find(|a| idle(a)) {
+            Some(oldest) if explore => *oldest,
+            _ => best,
+        };
+        self.unrank(addr);
+        let candidate = self
+            .pending
+            .remove(&addr)
+            .expect("ranked address is pending");
+        self.active.insert(addr, candidate.source_quality);
+        *self.active_ips.entry(addr.ip()).or_default() += 1;
+        Some(addr)
+    }
+    /// Finishes `addr` and queues it again at once with the source quality it was dialed with,
+    /// for a dial that failed through no fault of the address.
+    pub fn put_back(&mut self, addr: SocketAddr) {
+        let source_quality = self.finish(addr).unwrap_or(0.0);
+        self.push(addr, source_quality);
+    }
+    /// The dial or connection to `addr` ended, so it may be queued and dialed again. Returns the
+    /// source quality it was queued with if it was active.
+    fn finish(&mut self, addr: SocketAddr) -> Option<f64> {
+        let source_quality = self.active.remove(&addr)?;
+        if let Entry::Occupied(mut count) = self.active_ips.entry(addr.ip()) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+        Some(source_quality)
+    }
+    /// When the earliest waiting retry becomes due, or a pause ends with addresses waiting.
+    pub fn next_retry(&self) -> Option<Instant> {
+        let retry = self
+            .waiting
+            .first()
+            .and_then(|(at, _, _)| Some(*at).max(self.paused_until));
+        let paused = self.paused_until.filter(|_| !self.ranked.is_empty());
+        [retry, paused].into_iter().flatten().min()
+    }
+    /// Hands nothing out until `until`.
+    pub fn pause(&mut self, until: Instant) {
+        self.paused_until = self.paused_until.max(Some(until));
+    }
+    pub fn record_success(&mut self, addr: SocketAddr, connect_time: Duration, now: Instant) {
+        self.update_history(addr, now).successes += 1;
+        self.rescore(addr);
+        if connect_time < FAST_CONNECT && self.fast_subnets.insert(subnet(addr.ip())) {
+            // Rare enough that rescoring every pending address is cheap overall.
+            let promoted = self
+                .pending
+                .keys()
+                .filter(|a| subnet(a.ip()) == subnet(addr.ip()))
+                .copied()
+                .collect::<Vec<_>>();
+            for a in promoted {
+                self.rescore(a);
+            }
+        }
+    }
+    /// Records a failed dial, which finishes it, and given a `backoff` queues a retry after it.
+    /// The backoff doubles with each failure and the address is dropped after
+    /// `MAX_DIAL_FAILURES`.
+    pub fn record_failure(&mut self, addr: SocketAddr, backoff: Option<Duration>, now: Instant) {
+        let source_quality = self.finish(addr).unwrap_or(0.0);
+        let history = self.update_history(addr, now);
+        history.failures += 1;
+        let failures = history.failures;
+        self.rescore(addr);
+        let Some(backoff) = backoff else {
+            return;
+        };
+        if failures < MAX_DIAL_FAILURES {
+            let not_before = now + backoff * 2u32.pow(failures - 1);
+            self.requeue(addr, source_quality, not_before);
+        }
+    }
+    /// Given a `backoff`, queues a connection that ended cleanly to be dialed again after it.
+    /// Unlike `record_failure` this counts nothing against the address.
+    pub fn retry(&mut self, addr: SocketAddr, backoff: Option<Duration>, now: Instant) {
+        let source_quality = self.finish(addr).unwrap_or(0.0);
+        if let Some(backoff) = backoff {
+            self.requeue(addr, source_quality, now + backoff);
+        }
+    }
+    fn requeue(&mut self, addr: SocketAddr, source_quality: f64, not_before: Instant) {
+        if !self.pending.contains_key(&addr) {
+            self.insert(addr, source_quality, Some(not_before));
+        }
+    }
+    fn insert(&mut self, addr: SocketAddr, source_quality: f64, not_before: Option<Instant>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.insert(
+            addr,
+            Candidate {
+                source_quality,
+                seq,
+                not_before,
+                score: None,
+            },
+        );
+        match not_before {
+            Some(at) => {
+                self.waiting.insert((at, seq, addr));
+            }
+            None => self.rank(addr),
+        }
+    }
+    /// Adds a due candidate to `ranked`, and to `unknown` if it has no score.
+    fn rank(&mut self, addr: SocketAddr) {
+        let score = self.score(addr);
+        let candidate = self
+            .pending
+            .get_mut(&addr)
+            .expect("ranked address is pending");
+        candidate.score = score;
+        self.ranked
+            .insert((Reverse(score_key(score)), candidate.seq), addr);
+        if score.is_none() {
+            self.unknown.insert(candidate.seq, addr);
+        }
+    }
+    fn unrank(&mut self, addr: SocketAddr) {
+        let candidate = &self.pending[&addr];
+        self.ranked
+            .remove(&(Reverse(score_key(candidate.score)), candidate.seq));
+        self.unknown.remove(&candidate.seq);
+    }
+    /// Ranks a due candidate again after something its score depends on changed.
+    fn rescore(&mut self, addr: SocketAddr) {
+        if self
+            .pending
+            .get(&addr)
+            .is_some_and(|c| c.not_before.is_none())
+        {
+            self.unrank(addr);
+            self.rank(addr);
+        }
+    }
+    /// `addr`'s history, marked updated at `now`. Entries not updated for `HISTORY_EXPIRY` are
+    /// forgotten, checking at most once per `HISTORY_EXPIRY / 2`.
+    fn update_history(&mut self, addr: SocketAddr, now: Instant) -> &mut DialHistory {
+        if self
+            .history_expired
+            .is_none_or(|at| now >= at + HISTORY_EXPIRY / 2)
+        {
+            self.history_expired = Some(now);
+            let expired = self
+                .history
+                .iter()
+                .filter(|(_, entry)| now >= entry.updated + HISTORY_EXPIRY)
+                .map(|(a, _)| *a)
+                .collect::<Vec<_>>();
+            for a in expired {
+                self.history.remove(&a);
+                self.rescore(a);
+            }
+        }
+        let entry = self.history.entry(addr).or_insert(HistoryEntry {
+            history: DialHistory::default(),
+            updated: now,
+        });
+        entry.updated = now;
+        &mut entry.history
+    }
+    fn score(&self, addr: SocketAddr) -> Option<f64> {
+        dial_score(
+            self.pending[&addr].source_quality,
+            self.history
+                .get(&addr)
+                .map(|entry| entry.history)
+                .unwrap_or_default(),
+            self.fast_subnets.contains(&subnet(addr.ip())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+    use std::collections::VecDeque;
+
+    fn addr(a: u8, b: u8, c: u8, d: u8) -> SocketAddr {
+        SocketAddr::from(([a, b, c, d], 6881))
+    }
+
+    #[test]
+    fn test_dial_score() {
+        assert_eq!(dial_score(0.0, DialHistory::default(), false), None);
+        let good = DialHistory {
+            successes: 2,
+            failures: 0,
+        };
+        let bad = DialHistory {
+            successes: 0,
+            failures: 2,
+        };
+        let good = dial_score(0.0, good, false).unwrap();
+        let bad = dial_score(0.0, bad, false).unwrap();
+        let subnet = dial_score(0.0, DialHistory::default(), true).unwrap();
+        let tracker = dial_score(0.3, DialHistory::default(), false).unwrap();
+        assert!(good > subnet && subnet > tracker && tracker > 0.0 && 0.0 > bad);
+    }
+
+    #[test]
+    fn test_queue_order_and_exploration() {
+        let mut queue = DialQueue::default();
+        let unknown = [addr(1, 1, 1, 1), addr(2, 2, 2, 2), addr(3, 3, 3, 3)];
+        let failed = addr(4, 4, 4, 4);
+        let known = [addr(5, 5, 5, 5), addr(6, 6, 6, 6), addr(7, 7, 7, 7)];
+        queue.record_failure(failed, None, Instant::now());
+        for a in known {
+            queue.record_success(a, Duration::from_secs(1), Instant::now());
+        }
+        for a in unknown.iter().chain([&failed]).chain(known.iter()) {
+            assert!(queue.push(*a, 0.0));
+        }
+        assert!(!queue.push(unknown[0], 0.0));
+
+        let now = Instant::now();
+        let order = std::iter::from_fn(|| queue.pop(now)).collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![known[0], known[1], known[2], unknown[0], unknown[1], unknown[2], failed]
+        );
+    }
+
+    #[test]
+    fn test_fast_subnet_is_promoted() {
+        let mut queue = DialQueue::default();
+        queue.push(addr(1, 1, 1, 1), 0.0);
+        queue.push(addr(10, 1, 9, 9), 0.0);
+        queue.record_success(addr(10, 1, 0, 1), Duration::from_millis(20), Instant::now());
+        assert_eq!(queue.pop(Instant::now()), Some(addr(10, 1, 9, 9)));
+    }
+
+    #[test]
+    fn test_retry_waits_for_backoff() {
+        let mut queue = DialQueue::default();
+        let busy = addr(1, 1, 1, 1);
+        let start = Instant::now();
+        let backoff = Duration::from_secs(15);
+        queue.push(busy, 0.0);
+        assert_eq!(queue.pop(start), Some(busy));
+
+        queue.record_failure(busy, Some(backoff), start);
+        assert_eq!(queue.next_retry(), Some(start + backoff));
+        assert_eq!(queue.pop(start), None);
+        assert_eq!(queue.pop(start + backoff), Some(busy));
+
+        // Each further failure doubles the wait, and the address is given up on eventually.
+        queue.record_failure(busy, Some(backoff), start);
+        assert_eq!(queue.next_retry(), Some(start + backoff * 2));
+        queue.pop(start + backoff * 2);
+        for _ in 2..MAX_DIAL_FAILURES {
+            queue.record_failure(busy, Some(backoff), start);
+            queue.pop(start + Duration::from_secs(3600));
+        }
+        assert_eq!(queue.next_retry(), None);
+    }
+
+    #[test]
+    fn test_active_address_is_not_queued_again() {
+        let mut queue = DialQueue::default();
+        let peer = addr(1, 1, 1, 1);
+        let other_port = SocketAddr::new(peer.ip(), 51413);
+        let unknown = addr(2, 2, 2, 2);
+        assert!(queue.push(unknown, 0.0));
+        assert!(queue.push(peer, 0.0));
+        // A second source reporting the same address raises its quality instead.
+        assert!(!queue.push(peer, 0.3));
+        assert_eq!(queue.pop(Instant::now()), Some(peer));
+
+        assert!(!queue.push(peer, 1.0));
+        assert!(queue.push(other_port, 1.0));
+        assert_eq!(queue.pop(Instant::now()), Some(unknown));
+        assert_eq!(queue.pop(Instant::now()), None);
+
+        queue.finish(peer);
+        assert!(queue.push(peer, 0.0));
+        assert_eq!(queue.pop(Instant::now()), Some(other_port));
+        assert_eq!(queue.pop(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_put_back_keeps_source_quality() {
+        let mut queue = DialQueue::default();
+        let tracked = addr(1, 1, 1, 1);
+        let unknown = addr(2, 2, 2, 2);
+        let now = Instant::now();
+        queue.push(tracked, 0.3);
+        assert_eq!(queue.pop(now), Some(tracked));
+        queue.push(unknown, 0.0);
+        queue.put_back(tracked);
+        assert_eq!(queue.pending[&tracked].source_quality, 0.3);
+        assert_eq!(queue.pop(now), Some(tracked));
+    }
+
+    #[test]
+    fn test_pause_holds_everything_back() {
+        let mut queue = DialQueue::default();
+        let start = Instant::now();
+        let pause = Duration::from_secs(30);
+        queue.pause(start + pause);
+        assert_eq!(queue.next_retry(), None);
+        queue.push(addr(1, 1, 1, 1), 0.0);
+        assert_eq!(queue.next_retry(), Some(start + pause));
+        assert_eq!(queue.pop(start), None);
+        assert_eq!(queue.pop(start + pause), Some(addr(1, 1, 1, 1)));
+    }
+
+    #[test]
+    fn test_history_expires() {
+        let mut queue = DialQueue::default();
+        let failed = addr(1, 1, 1, 1);
+        let fresh = addr(2, 2, 2, 2);
+        let start = Instant::now();
+        queue.record_failure(failed, None, start);
+        queue.push(failed, 0.0);
+        queue.push(fresh, 0.3);
+        assert_eq!(queue.pop(start), Some(fresh));
+        queue.finish(fresh);
+
+        // Any later record past the expiry forgets the failure, which ranks the address again.
+        queue.push(fresh, 0.0);
+        queue.record_success(fresh, Duration::from_secs(1), start + HISTORY_EXPIRY);
+        assert!(!queue.history.contains_key(&failed));
+        assert_eq!(queue.pending[&failed].score, None);
+        assert_eq!(queue.pop(start + HISTORY_EXPIRY), Some(fresh));
+        assert_eq!(queue.pop(start + HISTORY_EXPIRY), Some(failed));
+    }
+
+    const SLOTS: usize = 4;
+    const WANTED: usize = 10;
+
+    /// Ticks until `WANTED` good peers are connected, dialing `SLOTS` at a time. Good peers
+    /// connect in one tick; the rest time out after five.
+    fn simulate(
+        pool: &[SocketAddr],
+        good: &HashSet<SocketAddr>,
+        resumed: SocketAddr,
+        ordered: bool,
+    ) -> u32 {
+        let mut queue = DialQueue::default();
+        queue.record_success(resumed, Duration::from_millis(50), Instant::now());
+        let mut fifo = VecDeque::new();
+        for a in pool {
+            queue.push(*a, 0.0);
+            fifo.push_back(*a);
+        }
+        let mut in_flight: Vec<(u32, SocketAddr)> = Vec::new();
+        let mut connected = 0;
+        for tick in 0.. {
+            in_flight.retain(|(done, a)| {
+                if *done != tick {
+                    return true;
+                }
+                if good.contains(a) {
+                    connected += 1;
+                    queue.record_success(*a, Duration::from_millis(50), Instant::now());
+                } else {
+                    queue.record_failure(*a, None, Instant::now());
+                }
+                false
+            });
+            if connected >= WANTED {
+                return tick;
+            }
+            while in_flight.len() < SLOTS {
+                let next = if ordered {
+                    queue.pop(Instant::now())
+                } else {
+                    fifo.pop_front()
+                };
+                let Some(a) = next else { break };
+                let cost = if good.contains(&a) { 1 } else { 5 };
+                in_flight.push((tick + cost, a));
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_ordered_dialing_beats_fifo() {
+        let mut rng = StdRng::seed_from_u64(1177);
+        let good = (1..=20).map(|i| addr(10, 1, 0, i)).collect::<HashSet<_>>();
+        let mut pool = good.iter().copied().collect::<Vec<_>>();
+        while pool.len() < 200 {
+            pool.push(addr(
+                rng.gen_range(20..200),
+                rng.gen(),
+                rng.gen(),
+                rng.gen(),
+            ));
+        }
+        pool.shuffle(&mut rng);
+        let resumed = *good.iter().next().unwrap();
+
+        let fifo = simulate(&pool, &good, resumed, false);
+        let ordered = simulate(&pool, &good, resumed, true);
+        assert!(ordered * 4 < fifo, "ordered {} vs fifo {}", ordered, fifo);
+    }
+}
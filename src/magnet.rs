@@ -4,39 +4,140 @@ pub struct Magnet {
     pub display_name: String,
 }
 impl Magnet {
-    pub fn from_link_string(value: &str) -> Self {
-        let decoded = urlencoding::decode(&value).expect("Failed to parse magnet link");
-        let slice = &decoded[8..];
-        let split = slice.split("&").collect::<Vec<_>>();
+    pub fn from_link_string(value: &str) -> anyhow::Result<Self> {
+        let Some(query) = value.strip_prefix("magnet:?") else {
+            anyhow::bail!("Not a magnet link");
+        };
 
         let mut trackers = Vec::new();
-        let mut exact_topic = [0u8; 20];
+        let mut exact_topic = None;
         let mut display_name = String::new();
-        for item in split {
-            let (id, value) = item.split_once("=").unwrap();
+        // Split before decoding so that an escaped '&' inside a value stays part of it.
+        for item in query.split('&') {
+            let Some((id, value)) = item.split_once('=') else {
+                continue;
+            };
+            let value = urlencoding::decode(value)
+                .map_err(|e| anyhow::anyhow!("Invalid escape in magnet {} parameter: {}", id, e))?;
             match id {
                 "xt" => {
-                    let info_string = value[value.len() - 40..].as_bytes();
-                    let bytes = hex::decode(info_string)
-                        .expect("Failed to parse info hash from magnet link");
-                    exact_topic.copy_from_slice(bytes.as_slice());
+                    // Other topics, such as the urn:btmh: of hybrid v1/v2 links, are skipped.
+                    if let Some(info_string) = value.strip_prefix("urn:btih:") {
+                        exact_topic = Some(decode_btih(info_string)?);
+                    }
                 }
                 "dn" => {
-                    display_name = String::from(value);
+                    display_name = value.into_owned();
                 }
                 "tr" => {
                     use std::str::FromStr;
-                    if let Some(tracker) = url::Url::from_str(value).ok() {
+                    if let Ok(tracker) = url::Url::from_str(&value) {
                         trackers.push(tracker);
                     }
                 }
                 &_ => (),
             }
         }
-        Self {
+        Ok(Self {
             tracker_urls: trackers,
-            info_hash: exact_topic,
+            info_hash: exact_topic
+                .ok_or_else(|| anyhow::anyhow!("Magnet link has no urn:btih topic"))?,
             display_name,
+        })
+    }
+}
+
+/// Decodes a v1 info hash given as 40 hex digits or 32 base32 characters.
+fn decode_btih(info_string: &str) -> anyhow::Result<[u8; 20]> {
+    let mut info_hash = [0u8; 20];
+    if info_string.len() == 32 {
+        let mut bits = 0u64;
+        let mut bit_count = 0;
+        let mut out = info_hash.iter_mut();
+        for c in info_string.bytes() {
+            let value = match c.to_ascii_uppercase() {
+                c @ b'A'..=b'Z' => c - b'A',
+                c @ b'2'..=b'7' => c - b'2' + 26,
+                _ => anyhow::bail!("Invalid base32 info hash {}", info_string),
+            };
+            bits = bits << 5 | value as u64;
+            bit_count += 5;
+            if bit_count >= 8 {
+                bit_count -= 8;
+                *out.next().unwrap() = (bits >> bit_count) as u8;
+            }
+        }
+        return Ok(info_hash);
+    }
+    hex::decode_to_slice(info_string, &mut info_hash)
+        .map_err(|e| anyhow::anyhow!("Invalid info hash {}: {}", info_string, e))?;
+    Ok(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{arbitrary_bytes, corrupt};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const LINK: &str = "magnet:?xt=urn:btih:73103935E5CA2B132DA9C5B716A012CEFC67E6BA&dn=Some%20Name&tr=udp%3A%2F%2Ftracker.example%3A1337%2Fannounce%3Fa%3D1%26b%3D2";
+
+    #[test]
+    fn test_parse_link() {
+        let magnet = Magnet::from_link_string(LINK).unwrap();
+        assert_eq!(
+            hex::encode_upper(magnet.info_hash),
+            "73103935E5CA2B132DA9C5B716A012CEFC67E6BA"
+        );
+        assert_eq!(magnet.display_name, "Some Name");
+        assert_eq!(
+            magnet.tracker_urls[0].as_str(),
+            "udp://tracker.example:1337/announce?a=1&b=2"
+        );
+
+        assert!(Magnet::from_link_string("").is_err());
+        assert!(Magnet::from_link_string("magnet:?dn=x").is_err());
+        assert!(Magnet::from_link_string("magnet:?xt=urn:btih:abc").is_err());
+        assert!(Magnet::from_link_string("magnet:?xt=urn:sha1:abc").is_err());
+    }
+
+    #[test]
+    fn test_hybrid_and_base32_topics() {
+        let hybrid = "magnet:?xt=urn:btmh:1220d2474e86c95b19b8bcfdb92bc12c9d44667cfa36d2474e86c95b19b8bcfdb92b\
+                      &xt=urn:btih:73103935e5ca2b132da9c5b716a012cefc67e6ba&dn=x";
+        let magnet = Magnet::from_link_string(hybrid).unwrap();
+        assert_eq!(
+            hex::encode_upper(magnet.info_hash),
+            "73103935E5CA2B132DA9C5B716A012CEFC67E6BA"
+        );
+
+        // The same info hash in base32, in either case.
+        for topic in [
+            "OMIDSNPFZIVRGLNJYW3RNIASZ36GPZV2",
+            "omidsnpfzivrglnjyw3rniasz36gpzv2",
+        ] {
+            let link = format!("magnet:?xt=urn:btih:{}", topic);
+            let magnet = Magnet::from_link_string(&link).unwrap();
+            assert_eq!(
+                hex::encode_upper(magnet.info_hash),
+                "73103935E5CA2B132DA9C5B716A012CEFC67E6BA"
+            );
+        }
+        assert!(
+            Magnet::from_link_string("magnet:?xt=urn:btih:OMIDSNPFZIVRGLNJYW3RNIASZ36GPZV1")
+                .is_err()
+        );
+        assert!(Magnet::from_link_string("magnet:?xt=urn:btmh:1220d2474e86").is_err());
+    }
+
+    #[test]
+    fn test_hostile_links_never_panic() {
+        let mut rng = StdRng::seed_from_u64(1200);
+        for _ in 0..5000 {
+            let bytes = corrupt(&mut rng, LINK.as_bytes());
+            let _ = Magnet::from_link_string(&String::from_utf8_lossy(&bytes));
+            let bytes = arbitrary_bytes(&mut rng, 200);
+            let _ = Magnet::from_link_string(&String::from_utf8_lossy(&bytes));
         }
     }
 }
@@ -1,13 +1,42 @@
+mod bitfield;
+mod cli;
+mod dial_queue;
 mod magnet;
+mod peer_capabilities;
 mod peer_codec;
+mod peer_failure;
+mod peer_link;
 mod peer_message;
+mod peer_throttle;
+mod peer_trace;
+mod peer_view;
+mod resolver;
+#[cfg(test)]
+mod testutil;
+mod tracker_history;
 mod tracker_stream;
+mod violations;
+mod wire_stats;
+use bitfield::PeerPieces;
 use byteorder::{BigEndian, ByteOrder};
+use dial_queue::DialQueue;
 use bytes::{Bytes, BytesMut};
-use futures::{SinkExt, StreamExt, stream::SplitStream};
-use peer_codec::{Handshake, PeerCodec, PeerFrame, BITTORRENT_PROTOCOL, Data};
+use futures::{Sink, SinkExt, StreamExt, stream::SplitStream};
+use peer_capabilities::{Capabilities, Quirk};
+use peer_codec::{Handshake, PeerCodec, PeerFrame, BITTORRENT_PROTOCOL};
+use peer_failure::{DisconnectReason, FailureClass, FailureStats, PeerFailure, Superseded};
+use peer_link::{LinkEvent, PeerLink};
 use peer_message::{PeerMessage, PeerMessageType};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use peer_throttle::{PeerThrottle, ThrottleConfig, Verdict};
+use peer_trace::{PeerTrace, TraceConfig};
+use peer_view::{PeerSort, PeerStats};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 use tokio_util::codec::Framed;
 
 use magnet::Magnet;
@@ -16,35 +45,90 @@ use tokio::{
     net::TcpStream,
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        Mutex, RwLock,
+        RwLock, Semaphore,
     },
 };
-use tracker_stream::Trackers;
+use resolver::Resolver;
+use tracker_history::TrackerHistory;
+use tracker_stream::{swarm_has_no_seeders, Trackers};
+use violations::{Violation, ViolationReport};
+use wire_stats::WireStats;
+
+const SAMPLE_LINK: &str = "magnet:?xt=urn:btih:73103935E5CA2B132DA9C5B716A012CEFC67E6BA&dn=Succession.S03E06.1080p.WEB.H264-CAKES&tr=http%3A%2F%2Ftracker.trackerfix.com%3A80%2Fannounce&tr=udp%3A%2F%2F9.rarbg.me%3A2800%2Fannounce&tr=udp%3A%2F%2F9.rarbg.to%3A2950%2Fannounce&tr=udp%3A%2F%2Ftracker.thinelephant.org%3A12740%2Fannounce&tr=udp%3A%2F%2Ftracker.fatkhoala.org%3A13720%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let link = "magnet:?xt=urn:btih:73103935E5CA2B132DA9C5B716A012CEFC67E6BA&dn=Succession.S03E06.1080p.WEB.H264-CAKES&tr=http%3A%2F%2Ftracker.trackerfix.com%3A80%2Fannounce&tr=udp%3A%2F%2F9.rarbg.me%3A2800%2Fannounce&tr=udp%3A%2F%2F9.rarbg.to%3A2950%2Fannounce&tr=udp%3A%2F%2Ftracker.thinelephant.org%3A12740%2Fannounce&tr=udp%3A%2F%2Ftracker.fatkhoala.org%3A13720%2Fannounce&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Ftracker.openbittorrent.com%3A80%2Fannounce&tr=udp%3A%2F%2Fopentracker.i2p.rocks%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.internetwarriors.net%3A1337%2Fannounce&tr=udp%3A%2F%2Ftracker.leechers-paradise.org%3A6969%2Fannounce&tr=udp%3A%2F%2Fcoppersurfer.tk%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.zer0day.to%3A1337%2Fannounce";
-    let magnet = Magnet::from_link_string(link);
-
-    let state = Arc::new(RwLock::new(Shared::new(magnet.info_hash.to_vec().into())));
+    let (
+        link,
+        add_trackers,
+        trace,
+        strict,
+        report,
+        check_invariants,
+        peers_view,
+        tracker_buffer,
+        tracker_history,
+    ) = match cli::Command::parse(std::env::args().skip(1))? {
+        cli::Command::Info { link, swarm } => return info(&link, swarm).await,
+        cli::Command::Download {
+            link,
+            add_trackers,
+            trace,
+            strict,
+            report,
+            check_invariants,
+            peers_view,
+            tracker_buffer,
+            tracker_history,
+        } => (
+            link.unwrap_or_else(|| SAMPLE_LINK.to_string()),
+            add_trackers,
+            trace,
+            strict,
+            report,
+            check_invariants,
+            peers_view,
+            tracker_buffer,
+            tracker_history,
+        ),
+    };
+    let magnet = Magnet::from_link_string(&link)?;
+    require_peer_source(&magnet, &add_trackers)?;
 
-    let trackers = Trackers::new(&magnet.tracker_urls).await;
+    let mut shared = Shared::new(magnet.info_hash.to_vec().into());
+    shared.trace = trace;
+    shared.strict = strict;
+    shared.report_path = report;
+    shared.check_invariants = check_invariants;
+    let state = Arc::new(RwLock::new(shared));
 
-    {
-        let state_lock = state.read().await;
-        let peers = trackers
-            .announce(state_lock.peer_id.clone(), magnet.info_hash.to_vec().into())
-            .await;
-        for addr in peers.into_iter() {
-            let state = Arc::clone(&state);
-            tokio::spawn(async move {
-                if let Err(e) = peer_process(state, addr).await {
-                    println!("{:#}", e);
-                }
-            });
+    let history = match &tracker_history {
+        Some(path) => TrackerHistory::load(path, SystemTime::now()).unwrap_or_else(|e| {
+            println!("Ignoring tracker history: {:#}", e);
+            TrackerHistory::default()
+        }),
+        None => TrackerHistory::default(),
+    };
+    let mut trackers =
+        Trackers::with_history(&magnet.tracker_urls, Arc::new(Resolver::system()), history);
+    if let Some(bytes) = tracker_buffer {
+        trackers.set_receive_buffer(bytes);
+    }
+    for url in add_trackers {
+        if !trackers.add(url.clone()) {
+            println!("Tracker {} is already listed", url);
         }
     }
+    tokio::spawn(announce_and_dial(
+        Arc::clone(&state),
+        Arc::new(trackers),
+        magnet.info_hash.to_vec().into(),
+        tracker_history,
+    ));
 
+    if let Some(sort) = peers_view {
+        return show_peers(&state, sort).await;
+    }
     loop {
         tokio::task::yield_now().await;
         let state = state.read().await;
@@ -53,98 +137,396 @@ async fn main() -> anyhow::Result<()> {
             state
                 .peer_state
                 .values()
-                .fold(0, |a, p| if !p.am_choked { a + 1 } else { a });
+                .filter(|p| p.link.interest().is_some_and(|i| !i.am_choked))
+                .count();
         println!("Unchoked Peers: {}/{}", unchoked_peers, peers);
     }
 }
 
-async fn peer_process(state: Arc<RwLock<Shared>>, addr: SocketAddr) -> anyhow::Result<()> {
+/// Distinct tracker peers after which the remaining announces are abandoned; more than this
+/// would sit in the dial queue unused.
+const SUFFICIENT_PEERS: usize = 3 * MAX_PEER_CONNECTIONS;
+
+/// Queues each tracker's peers as soon as its announce returns, and starts the dialer again
+/// whenever it has run out of addresses before more arrive. Once the announces are done, the
+/// tracker history is saved to `history_path`.
+async fn announce_and_dial(
+    state: Arc<RwLock<Shared>>,
+    trackers: Arc<Trackers>,
+    info_hash: Bytes,
+    history_path: Option<PathBuf>,
+) {
+    let peer_id = state.read().await.peer_id.clone();
+    let mut dialer: Option<tokio::task::JoinHandle<()>> = None;
+    let announces = trackers.announce_until(peer_id, info_hash, SUFFICIENT_PEERS);
+    tokio::pin!(announces);
+    while let Some(announced) = announces.next().await {
+        {
+            let mut state = state.write().await;
+            for addr in announced.peers {
+                state.dials.push(addr, announced.quality);
+            }
+        }
+        if dialer.as_ref().is_none_or(|dialer| dialer.is_finished()) {
+            dialer = Some(tokio::spawn(dial_peers(Arc::clone(&state))));
+        }
+    }
+    for (url, metrics) in trackers.status() {
+        println!("{}: {}", url, metrics);
+    }
+    if let Some(path) = history_path {
+        if let Err(e) = trackers.updated_history(SystemTime::now()).save(&path) {
+            println!("Failed to save tracker history: {:#}", e);
+        }
+    }
+}
+
+/// `--peers-view`: redraws the peer table every second. When stdout is not a terminal the
+/// tables are appended instead, one per refresh.
+async fn show_peers(state: &RwLock<Shared>, sort: PeerSort) -> anyhow::Result<()> {
+    use std::io::IsTerminal;
+    let tty = std::io::stdout().is_terminal();
+    loop {
+        let (stats, inbound, outbound) = {
+            let state = state.read().await;
+            (state.peer_stats(Instant::now()), state.wire.inbound(), state.wire.outbound())
+        };
+        if tty {
+            print!("\x1b[2J\x1b[H");
+        }
+        println!("{}", peer_view::render(&stats, sort, peer_view::PEER_VIEW_ROWS));
+        println!(
+            "Overhead: {:.1}% of {} bytes in, {:.1}% of {} bytes out",
+            inbound.overhead_percent(),
+            inbound.total(),
+            outbound.overhead_percent(),
+            outbound.total()
+        );
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Trackers are currently the only way to find peers, so a magnet without any (e.g. a bare
+/// `magnet:?xt=urn:btih:<hash>`) would otherwise sit idle with no explanation.
+fn require_peer_source(magnet: &Magnet, add_trackers: &[url::Url]) -> anyhow::Result<()> {
+    if magnet.tracker_urls.is_empty() && add_trackers.is_empty() {
+        anyhow::bail!(
+            "Magnet link has no trackers and DHT is not supported, so no peers can be found. \
+             Supply a tracker with --add-tracker <url>"
+        );
+    }
+    Ok(())
+}
+
+/// `magdl info`: describe the magnet link and, with `--swarm`, scrape its trackers. Fails when
+/// no tracker reports a seeder so that scripts can skip dead torrents.
+async fn info(link: &str, swarm: bool) -> anyhow::Result<()> {
+    let magnet = Magnet::from_link_string(link)?;
+    println!("Name:      {}", magnet.display_name);
+    println!("Info hash: {}", hex::encode(magnet.info_hash));
+    println!("Trackers:  {}", magnet.tracker_urls.len());
+    if !swarm {
+        return Ok(());
+    }
+
+    let trackers = Trackers::new(&magnet.tracker_urls);
+    let results = trackers.scrape(magnet.info_hash.to_vec().into()).await;
+    println!();
+    println!("{:<50} {:>8} {:>8} {:>10}", "Tracker", "Seeders", "Leechers", "Completed");
+    for (url, result) in results.iter() {
+        match result {
+            Ok(stats) => println!(
+                "{:<50} {:>8} {:>8} {:>10}",
+                url.as_str(),
+                stats.seeders,
+                stats.leechers,
+                stats.completed
+            ),
+            Err(e) => println!("{:<50} failed: {:#}", url.as_str(), e),
+        }
+    }
+    if swarm_has_no_seeders(&results) {
+        anyhow::bail!("No tracker reports any seeders");
+    }
+    Ok(())
+}
+
+/// Upper bound on concurrently running peer tasks, so that the dial order matters.
+const MAX_PEER_CONNECTIONS: usize = 50;
+/// Floor for the connection limit as file descriptor shortages lower it.
+const MIN_PEER_CONNECTIONS: usize = 4;
+/// How often the dialer looks for due retries while nothing is ready.
+const DIAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long dialing stops after the process runs out of file descriptors.
+const FILE_LIMIT_PAUSE: Duration = Duration::from_secs(30);
+
+/// Dials queued peers best first until the queue is empty, keeping at most
+/// `Shared::connection_limit` peer tasks alive.
+async fn dial_peers(state: Arc<RwLock<Shared>>) {
+    let slots = Arc::new(Semaphore::new(state.read().await.connection_limit));
+    loop {
+        let permit = Arc::clone(&slots)
+            .acquire_owned()
+            .await
+            .expect("dial semaphore is never closed");
+        let now = Instant::now();
+        let (next, retry_at, limit) = {
+            let mut state = state.write().await;
+            (state.dials.pop(now), state.dials.next_retry(), state.connection_limit)
+        };
+        let Some(addr) = next else {
+            drop(permit);
+            // Running tasks may still queue retries, so only stop once none are left.
+            if retry_at.is_none() && slots.available_permits() == limit {
+                return;
+            }
+            let wait = retry_at.map_or(DIAL_POLL_INTERVAL, |at| {
+                at.saturating_duration_since(now).min(DIAL_POLL_INTERVAL)
+            });
+            tokio::time::sleep(wait).await;
+            continue;
+        };
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let result = peer_process(Arc::clone(&state), addr).await;
+            match &result {
+                Ok(reason) => println!("Disconnected from {}: {}", addr, reason),
+                Err(failure) => println!(
+                    "Disconnected from {}: {} ({:#})",
+                    addr,
+                    failure.reason(),
+                    failure.error
+                ),
+            }
+            let mut state = state.write().await;
+            let lowered = state.peer_finished(addr, &result, Instant::now());
+            if let Some(dominant) = state.failures.record(result.err().map(|f| f.key())) {
+                println!("Warning: {}", dominant);
+                println!("{}", state.failures);
+            }
+            if lowered {
+                permit.forget();
+            } else {
+                drop(permit);
+            }
+        });
+    }
+}
+
+/// Runs one peer connection until it ends. A connection the peer closed cleanly is not a
+/// failure and comes back as `Ok(DisconnectReason::RemoteClosed)`.
+async fn peer_process(
+    state: Arc<RwLock<Shared>>,
+    addr: SocketAddr,
+) -> Result<DisconnectReason, PeerFailure> {
+    let (timeouts, trace, mut throttle, wire, check_invariants) = {
+        let state = state.read().await;
+        let throttle =
+            PeerThrottle::for_peer(&state.throttle, addr, state.piece_count, Instant::now());
+        let wire = Arc::new(WireStats::child(&state.wire));
+        let check_invariants = state.check_invariants;
+        (state.timeouts, state.trace.clone(), throttle, wire, check_invariants)
+    };
+    let started = Instant::now();
     let conn_future = TcpStream::connect(addr);
-    let conn = tokio::time::timeout(Duration::from_secs(5), conn_future).await??;
-    let framed = Framed::new(conn, PeerCodec::new());
+    let conn = tokio::time::timeout(timeouts.connect, conn_future)
+        .await
+        .map_err(|e| PeerFailure::new(FailureClass::ConnectTimeout, e))?
+        .map_err(PeerFailure::connect)?;
+    let connect_time = started.elapsed();
+    let mut link = PeerLink::Connecting;
+    link.on(LinkEvent::Connected, check_invariants)
+        .map_err(|e| PeerFailure::new(FailureClass::Io, e))?;
+    let codec = match trace {
+        Some(config) => PeerCodec::with_trace(PeerTrace::create(&config, addr).map_err(PeerFailure::io)?),
+        None => PeerCodec::new(),
+    }
+    .with_stats(Arc::clone(&wire));
+    let framed = Framed::new(conn, codec);
     let (mut sink, mut stream) = framed.split();
 
-    let info_hash = {
+    // One deadline for the whole connection, re-armed as the peer moves between phases.
+    let deadline = tokio::time::sleep(timeouts.handshake);
+    tokio::pin!(deadline);
+
+    let (info_hash, strict) = {
         let state = state.read().await;
         let handshake = Handshake {
             pstr: BITTORRENT_PROTOCOL.into(),
+            reserved: [0; 8],
             info_hash: state.info_hash.clone(),
             peer_id: state.peer_id.clone(),
         };
         let hs_frame = PeerFrame::Handshake(handshake);
-        sink.send(hs_frame).await?;
-        state.info_hash.clone()
+        sink.send(hs_frame).await.map_err(PeerFailure::handshake_io)?;
+        (state.info_hash.clone(), state.strict)
+    };
+    let handshake = tokio::select! {
+        frame = stream.next() => frame,
+        _ = &mut deadline => {
+            return Err(PeerFailure::new(
+                FailureClass::HandshakeTimeout,
+                anyhow::anyhow!("No handshake within {:?}", timeouts.handshake),
+            ));
+        }
     };
-    let process_peer_id = match stream.next().await {
+    let handshake = match handshake {
         Some(Ok(PeerFrame::Handshake(hs))) => {
+            // Outside strict mode another protocol string is only noted as a quirk.
+            if strict && hs.pstr != BITTORRENT_PROTOCOL.as_bytes() {
+                let detail = format!("Protocol string {:?}", String::from_utf8_lossy(&hs.pstr));
+                return Err(PeerFailure::new(
+                    FailureClass::ProtocolViolation,
+                    Violation::BadProtocolString.error(detail),
+                ));
+            }
             if hs.info_hash != info_hash {
-                anyhow::bail!("Bad info hash");
+                return Err(PeerFailure::new(
+                    FailureClass::Handshake,
+                    anyhow::anyhow!("Bad info hash"),
+                ));
             }
-            hs.peer_id
+            link.on(LinkEvent::HandshakeReceived, check_invariants)
+                .map_err(|e| PeerFailure::new(FailureClass::Handshake, e))?;
+            state.write().await.dials.record_success(addr, connect_time, Instant::now());
+            hs
         }
         Some(Ok(_)) => {
-            anyhow::bail!("No handshake received");
+            return Err(PeerFailure::new(
+                FailureClass::Handshake,
+                anyhow::anyhow!("No handshake received"),
+            ));
         }
         Some(Err(e)) => {
-            anyhow::bail!(e);
+            return Err(PeerFailure::handshake_io(e));
         }
         None => {
-            anyhow::bail!("Connection reset by peer");
+            return Err(PeerFailure::new(
+                FailureClass::EarlyDisconnect,
+                anyhow::anyhow!("Connection closed before handshake"),
+            ));
         }
     };
     let (tx, rx) = mpsc::unbounded_channel::<PeerMessage>();
-    let mut peer = Peer::new(process_peer_id, state.clone(), stream, addr, tx).await?;
+    let mut peer = Peer::new(handshake, state.clone(), stream, addr, tx, link, wire)
+        .await
+        .map_err(|e| PeerFailure::new(FailureClass::Io, e))?;
 
     tokio::spawn(async move {
-        let mut rx = rx;
-        let mut sink = sink;
-
-        while let Some(message) = rx.recv().await {
-            let frame = PeerFrame::Data(Data {
-                message_id: message.message_type.raw_value(),
-                payload: message.payload,
-            });
-            if let Err(e) = sink.send(frame).await {
-                dbg!(e);
-            };
+        if let Err(e) = peer_writer(rx, sink).await {
+            println!("Failed to write to {}: {:#}", addr, e);
         }
     });
 
-    while let Some(frame) = peer.stream.next().await {
+    loop {
+        deadline
+            .as_mut()
+            .reset(tokio::time::Instant::now() + timeouts.idle);
+        let frame = tokio::select! {
+            frame = peer.stream.next() => frame,
+            _ = &mut deadline => {
+                peer.cleanup().await;
+                return Err(PeerFailure::new(
+                    FailureClass::IdleTimeout,
+                    anyhow::anyhow!("No message within {:?}", timeouts.idle),
+                ));
+            }
+        };
         match frame {
-            Ok(PeerFrame::Data(data)) => {
-                let message = PeerMessage {
-                    message_type: PeerMessageType::from(data.message_id),
-                    payload: data.payload,
+            Some(Ok(PeerFrame::Data(data))) => {
+                let message = match PeerMessage::try_from(data) {
+                    Ok(message) => message,
+                    Err(e)
+                        if !strict
+                            && Violation::find(&e) == Some(Violation::UnknownMessageId) =>
+                    {
+                        // Outside strict mode the frame is skipped and the peer kept.
+                        peer.note_quirk(Quirk::UnknownMessageId).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        peer.cleanup().await;
+                        return Err(PeerFailure::new(FailureClass::ProtocolViolation, e));
+                    }
                 };
-                peer.handle_message(message).await;
+                if let Some(throttle) = throttle.as_mut() {
+                    match throttle.check(&message.message_type, Instant::now()) {
+                        Verdict::Accept => {}
+                        Verdict::Drop => continue,
+                        Verdict::Disconnect => {
+                            peer.cleanup().await;
+                            return Err(PeerFailure::new(
+                                FailureClass::ProtocolViolation,
+                                anyhow::anyhow!(
+                                    "Message flood ending in {:?}, {} messages dropped",
+                                    message.message_type,
+                                    throttle.dropped
+                                ),
+                            ));
+                        }
+                    }
+                }
+                let result = peer.handle_message(message).await;
+                if let Err(e) = result {
+                    peer.cleanup().await;
+                    let class = match e.is::<Superseded>() {
+                        true => FailureClass::Superseded,
+                        false => FailureClass::ProtocolViolation,
+                    };
+                    return Err(PeerFailure::new(class, e));
+                }
             }
-            Ok(_) => {
-                peer.cleanup().await?;
-                anyhow::bail!("Invalid message");
+            Some(Ok(PeerFrame::KeepAlive)) => continue,
+            Some(Ok(PeerFrame::Handshake(_))) => {
+                peer.cleanup().await;
+                return Err(PeerFailure::new(
+                    FailureClass::ProtocolViolation,
+                    anyhow::anyhow!("Invalid message"),
+                ));
             }
-            Err(e) => {
-                peer.cleanup().await?;
-                anyhow::bail!(e);
+            Some(Err(e)) => {
+                peer.cleanup().await;
+                return Err(PeerFailure::io(e));
             }
+            None => break,
+        }
+    }
+    peer.cleanup().await;
+    Ok(DisconnectReason::RemoteClosed)
+}
+
+/// Writes queued messages to the peer. Everything already queued when the writer wakes up is
+/// encoded into the sink's buffer and flushed together, so bursts of small control messages
+/// cost one write instead of one per message.
+async fn peer_writer<S>(mut rx: UnboundedReceiver<PeerMessage>, mut sink: S) -> Result<(), S::Error>
+where
+    S: Sink<PeerFrame> + Unpin,
+{
+    while let Some(message) = rx.recv().await {
+        sink.feed(message.into()).await?;
+        while let Ok(message) = rx.try_recv() {
+            sink.feed(message.into()).await?;
         }
+        sink.flush().await?;
     }
-    peer.cleanup().await?;
     Ok(())
 }
 
+#[allow(dead_code)]
 enum PieceStatus {
     NotStarted,
     RequestingBlock,
     Inactive,
     Complete,
 }
+#[allow(dead_code)]
 struct Piece {
     index: usize,
     status: PieceStatus,
     current_offset: usize,
     data: BytesMut,
 }
+#[allow(dead_code)]
 impl Piece {
     fn new(index: usize) -> Self {
         Self {
@@ -156,105 +538,199 @@ impl Piece {
     }
 }
 
+#[allow(dead_code)]
 struct PeerState {
-    choked: bool,
-    interested: bool,
-    am_choked: bool,
-    am_interested: bool,
-    bitfield: Vec<bool>,
+    link: PeerLink,
+    /// Messages applied so far, to tell whether a Bitfield came first.
+    received: usize,
+    /// Reject deviations that are otherwise tolerated.
+    strict: bool,
+    /// Check `link` invariants in release builds too.
+    check_invariants: bool,
+    pieces: PeerPieces,
     piece_queue: Vec<Piece>,
+    /// Client, advertised extensions and tolerated quirks.
+    capabilities: Capabilities,
+    connected: Instant,
+    /// Bytes exchanged with the peer, counted by its codec.
+    wire: Arc<WireStats>,
+    /// Identifies this connection among all connections made, including earlier ones to the
+    /// same address.
+    generation: u64,
 }
 impl PeerState {
-    async fn request_pieces(&mut self, tx: &UnboundedSender<PeerMessage>) -> anyhow::Result<()> {
-        todo!();
-
+    fn new(link: PeerLink, strict: bool) -> Self {
+        Self {
+            link,
+            received: 0,
+            strict,
+            check_invariants: false,
+            pieces: PeerPieces::default(),
+            piece_queue: Vec::new(),
+            capabilities: Capabilities::default(),
+            connected: Instant::now(),
+            wire: Arc::default(),
+            generation: 0,
+        }
     }
-    fn process_piece_message(&mut self, message: PeerMessage) {
+    /// Applies a message from the peer to our view of it.
+    fn apply(&mut self, message: PeerMessage, piece_count: Option<usize>) -> anyhow::Result<()> {
+        let event = LinkEvent::Received(message.message_type);
+        self.link.on(event, self.check_invariants)?;
+        if message.message_type == PeerMessageType::Bitfield && self.received > 0 {
+            if self.strict {
+                let detail = format!("Bitfield after {} other messages", self.received);
+                return Err(Violation::LateBitfield.error(detail));
+            }
+            self.capabilities.quirks.insert(Quirk::LateBitfield);
+        }
+        self.received += 1;
+        match message.message_type {
+            PeerMessageType::Choke
+            | PeerMessageType::Unchoke
+            | PeerMessageType::Interested
+            | PeerMessageType::NotInterested => {}
+            PeerMessageType::Have => {
+                if message.payload.len() != 4 {
+                    let detail = format!("Have with {} byte payload", message.payload.len());
+                    return Err(Violation::BadHaveLength.error(detail));
+                }
+                let index = BigEndian::read_u32(&message.payload);
+                let bits = self.pieces.known();
+                if bits.is_some_and(|bits| bits.get(index as usize) == Some(&true)) {
+                    self.capabilities.quirks.insert(Quirk::RedundantHave);
+                }
+                self.pieces.have(index, piece_count)?
+            }
+            PeerMessageType::Bitfield => self.pieces.bitfield(message.payload, piece_count)?,
+            // Nothing is requested yet, so any block is one we did not ask for.
+            PeerMessageType::Piece if self.strict => {
+                let detail = format!("Piece of {} bytes never requested", message.payload.len());
+                return Err(Violation::UnsolicitedPiece.error(detail));
+            }
+            // Nothing is uploaded or requested yet and there is no DHT, so these are ignored
+            // rather than trusted to never arrive.
+            PeerMessageType::Request
+            | PeerMessageType::Piece
+            | PeerMessageType::Cancel
+            | PeerMessageType::Port => {}
+        }
+        Ok(())
+    }
+    #[allow(dead_code)]
+    async fn request_pieces(&mut self, _tx: &UnboundedSender<PeerMessage>) -> anyhow::Result<()> {
         todo!();
-
     }
-    async fn express_interest(&mut self, tx: &UnboundedSender<PeerMessage>) -> anyhow::Result<()> {
+    #[allow(dead_code)]
+    fn process_piece_message(&mut self, _message: PeerMessage) {
         todo!();
-
     }
-}
-impl Default for PeerState {
-    fn default() -> Self {
-        Self {
-            choked: true,
-            interested: false,
-            am_choked: true,
-            am_interested: false,
-            bitfield: Vec::new(),
-            piece_queue: Vec::new(),
+    #[allow(dead_code)]
+    async fn express_interest(&mut self, tx: &UnboundedSender<PeerMessage>) -> anyhow::Result<()> {
+        let event = LinkEvent::SetInterested(true);
+        if let Some(message_type) = self.link.on(event, self.check_invariants)? {
+            tx.send(PeerMessage {
+                message_type,
+                payload: Bytes::new(),
+            })?;
         }
+        Ok(())
     }
 }
 
 pub struct Peer {
     shared: Arc<RwLock<Shared>>,
+    #[allow(dead_code)]
     process_peer_id: Bytes,
     stream: SplitStream<Framed<TcpStream, PeerCodec>>,
     addr: SocketAddr,
+    generation: u64,
 }
 impl Peer {
     async fn new(
-        process_peer_id: Bytes,
+        handshake: Handshake,
         shared: Arc<RwLock<Shared>>,
         stream: SplitStream<Framed<TcpStream, PeerCodec>>,
         addr: SocketAddr,
-        tx: UnboundedSender<PeerMessage>
+        tx: UnboundedSender<PeerMessage>,
+        link: PeerLink,
+        wire: Arc<WireStats>,
     ) -> anyhow::Result<Self> {
-        {
+        let generation = {
             let mut state = shared.write().await;
+            let generation = state.next_generation;
+            state.next_generation += 1;
+            // Any older connection to this address is superseded and ends when it notices.
             state.peer_channels.insert(addr, tx);
-            state.peer_state.insert(addr, PeerState::default());
-        }
+            let mut peer_state = PeerState::new(link, state.strict);
+            peer_state.check_invariants = state.check_invariants;
+            peer_state.capabilities = Capabilities::from_handshake(&handshake);
+            peer_state.wire = wire;
+            peer_state.generation = generation;
+            state.peer_state.insert(addr, peer_state);
+            generation
+        };
 
         Ok(Self {
             shared,
-            process_peer_id,
+            process_peer_id: handshake.peer_id,
             stream,
             addr,
+            generation,
         })
     }
-    async fn cleanup(&mut self) -> anyhow::Result<()> {
+    async fn cleanup(&mut self) {
         let mut state = self.shared.write().await;
+        let current = state.peer_state.get(&self.addr);
+        if current.is_none_or(|peer| peer.generation != self.generation) {
+            return;
+        }
         state.peer_channels.remove(&self.addr);
-        state.peer_state.remove(&self.addr);
-        Ok(())
+        let Some(peer) = state.peer_state.remove(&self.addr) else {
+            return;
+        };
+        // Kept for the violation report, which is written after the peer is gone.
+        if state.strict {
+            state.violations.describe(self.addr, peer.capabilities);
+        }
     }
-    async fn handle_message(&mut self, message: PeerMessage) {
+    async fn note_quirk(&mut self, quirk: Quirk) {
         let mut shared = self.shared.write().await;
-        let mut peer_state = shared.peer_state.get_mut(&self.addr).unwrap();
-        match message.message_type {
-            peer_message::PeerMessageType::Choke => peer_state.am_choked = true,
-            peer_message::PeerMessageType::Unchoke => peer_state.am_choked = false,
-            peer_message::PeerMessageType::Interested => peer_state.interested = true,
-            peer_message::PeerMessageType::NotInterested => peer_state.interested = false,
-            peer_message::PeerMessageType::Have => {
-                peer_state.bitfield[BigEndian::read_u32(&message.payload) as usize] = true
-            }
-            peer_message::PeerMessageType::Bitfield => {
-                peer_state.bitfield = Peer::process_bitfield(message)
+        if let Some(peer_state) = shared.peer_state.get_mut(&self.addr) {
+            if peer_state.generation == self.generation {
+                peer_state.capabilities.quirks.insert(quirk);
             }
-            peer_message::PeerMessageType::Request => todo!(),
-            peer_message::PeerMessageType::Piece => todo!(),
-            peer_message::PeerMessageType::Cancel => todo!(),
-            peer_message::PeerMessageType::Port => todo!(),
-        }
-    }
-    fn process_bitfield(message: PeerMessage) -> Vec<bool> {
-        let mask = 0b10000000;
-        let mut bits = Vec::new();
-        message.payload.into_iter().for_each(|byte| {
-            for i in 0..8 {
-                let s_mask = mask >> i;
-                let bit = (byte & s_mask) > 0;
-                bits.push(bit);
+        }
+    }
+    async fn handle_message(&mut self, message: PeerMessage) -> anyhow::Result<()> {
+        let mut shared = self.shared.write().await;
+        let piece_count = shared.piece_count;
+        match shared.peer_state.get_mut(&self.addr) {
+            Some(peer_state) if peer_state.generation == self.generation => {
+                peer_state.apply(message, piece_count)
             }
-        });
-        bits
+            _ => Err(Superseded.into()),
+        }
+    }
+}
+
+/// Per-phase limits for a peer connection. Each expiry is reported as its own failure class.
+#[derive(Debug, Clone, Copy)]
+struct PeerTimeouts {
+    /// TCP connect.
+    connect: Duration,
+    /// From connect until the remote handshake has been received.
+    handshake: Duration,
+    /// Maximum silence between messages once the handshake is done.
+    idle: Duration,
+}
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            handshake: Duration::from_secs(10),
+            idle: Duration::from_secs(120),
+        }
     }
 }
 
@@ -263,6 +739,26 @@ struct Shared {
     peer_id: Bytes,
     peer_channels: HashMap<SocketAddr, UnboundedSender<PeerMessage>>,
     peer_state: HashMap<SocketAddr, PeerState>,
+    /// Number of pieces in the torrent, known once metadata is available. Peer bitfields are
+    /// buffered until then and validated against it afterwards.
+    piece_count: Option<usize>,
+    /// Generation of the next peer connection.
+    next_generation: u64,
+    failures: FailureStats,
+    /// Bytes exchanged with all peers, split into block data and protocol overhead.
+    wire: Arc<WireStats>,
+    dials: DialQueue,
+    /// Peer connections allowed at once, lowered when the process runs out of file descriptors.
+    connection_limit: usize,
+    throttle: ThrottleConfig,
+    timeouts: PeerTimeouts,
+    trace: Option<TraceConfig>,
+    strict: bool,
+    violations: ViolationReport,
+    /// Check `PeerLink` invariants in release builds too.
+    check_invariants: bool,
+    /// `--report`: rewritten with `violations` whenever a violation is recorded.
+    report_path: Option<PathBuf>,
 }
 impl Shared {
     fn new(info_hash: Bytes) -> Self {
@@ -276,6 +772,656 @@ impl Shared {
             peer_id: peer_id.into(),
             peer_channels: HashMap::new(),
             peer_state: HashMap::new(),
+            piece_count: None,
+            next_generation: 0,
+            failures: FailureStats::default(),
+            wire: Arc::default(),
+            dials: DialQueue::default(),
+            connection_limit: MAX_PEER_CONNECTIONS,
+            throttle: ThrottleConfig::default(),
+            timeouts: PeerTimeouts::default(),
+            trace: None,
+            strict: false,
+            violations: ViolationReport::default(),
+            check_invariants: false,
+            report_path: None,
+        }
+    }
+    /// Snapshot of every connected peer for display.
+    fn peer_stats(&self, now: Instant) -> Vec<PeerStats> {
+        self.peer_state
+            .iter()
+            .map(|(addr, peer)| PeerStats {
+                addr: *addr,
+                capabilities: peer.capabilities.clone(),
+                interest: peer.link.interest(),
+                progress: peer.pieces.progress(),
+                inbound: peer.wire.inbound(),
+                outbound: peer.wire.outbound(),
+                age: now.saturating_duration_since(peer.connected),
+            })
+            .collect()
+    }
+    /// Dialing `addr` failed for lack of file descriptors. Dialing pauses, `addr` is queued
+    /// again, and the connection limit drops by one unless it is at its floor. Returns whether
+    /// it dropped, in which case the caller's connection slot is given up for good.
+    fn file_limit_reached(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        self.dials.pause(now + FILE_LIMIT_PAUSE);
+        self.dials.put_back(addr);
+        if self.connection_limit <= MIN_PEER_CONNECTIONS {
+            return false;
+        }
+        if self.connection_limit == MAX_PEER_CONNECTIONS {
+            println!(
+                "Out of file descriptors; pausing dials for {}s and lowering the peer \
+                 connection limit. Raise the open file limit (ulimit -n) to allow more peers.",
+                FILE_LIMIT_PAUSE.as_secs()
+            );
+        }
+        self.connection_limit -= 1;
+        true
+    }
+    /// The peer task for `addr` ended with `result`; queues the address again as its
+    /// disconnect reason allows. Returns `file_limit_reached`'s verdict for file limit failures.
+    fn peer_finished(
+        &mut self,
+        addr: SocketAddr,
+        result: &Result<DisconnectReason, PeerFailure>,
+        now: Instant,
+    ) -> bool {
+        let mut lowered = false;
+        match result {
+            Err(failure) if failure.class == FailureClass::FileLimit => {
+                lowered = self.file_limit_reached(addr, now);
+            }
+            Err(failure) => {
+                let backoff = failure.reason().retry_backoff();
+                self.dials.record_failure(addr, backoff, now);
+                if let Some(violation) = failure.violation.filter(|_| self.strict) {
+                    self.record_violation(addr, violation);
+                }
+            }
+            Ok(reason) => self.dials.retry(addr, reason.retry_backoff(), now),
+        }
+        self.violations.disconnected(addr);
+        lowered
+    }
+    fn record_violation(&mut self, addr: SocketAddr, violation: Violation) {
+        println!("Violation {} from {}", violation.code(), addr);
+        self.violations.record(addr, violation);
+        if let Some(path) = &self.report_path {
+            if let Err(e) = std::fs::write(path, self.violations.to_json()) {
+                println!("Failed to write {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::{io::AsyncWrite, sync::oneshot};
+    use tokio_util::codec::FramedWrite;
+
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        bytes: Vec<u8>,
+    }
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.bytes.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn test_state(timeouts: PeerTimeouts) -> Arc<RwLock<Shared>> {
+        let mut shared = Shared::new(vec![1u8; 20].into());
+        shared.timeouts = timeouts;
+        Arc::new(RwLock::new(shared))
+    }
+
+    const SHORT_TIMEOUTS: PeerTimeouts = PeerTimeouts {
+        connect: Duration::from_secs(5),
+        handshake: Duration::from_millis(100),
+        idle: Duration::from_millis(100),
+    };
+
+    #[test]
+    fn test_bare_magnet_requires_tracker() {
+        let bare = Magnet::from_link_string("magnet:?xt=urn:btih:73103935E5CA2B132DA9C5B716A012CEFC67E6BA").unwrap();
+        assert!(bare.tracker_urls.is_empty());
+        let error = require_peer_source(&bare, &[]).unwrap_err();
+        assert!(error.to_string().contains("--add-tracker"));
+
+        let added = url::Url::parse("udp://tracker.example:6969/announce").unwrap();
+        assert!(require_peer_source(&bare, &[added]).is_ok());
+        assert!(require_peer_source(&Magnet::from_link_string(SAMPLE_LINK).unwrap(), &[]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_conn, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let failure = peer_process(test_state(SHORT_TIMEOUTS), addr)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.class, FailureClass::HandshakeTimeout);
+    }
+
+    #[tokio::test]
+    async fn test_address_from_several_sources_is_dialed_once() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                held.push(conn);
+            }
+        });
+
+        let state = test_state(PeerTimeouts::default());
+        state.write().await.dials.push(addr, 0.0);
+        let sources = (0..4).map(|source| {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                for _ in 0..5 {
+                    state.write().await.dials.push(addr, source as f64 / 4.0);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        });
+        let dialer = tokio::spawn(dial_peers(Arc::clone(&state)));
+        futures::future::join_all(sources).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        dialer.abort();
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_file_limit_lowers_connection_limit() {
+        let mut shared = Shared::new(vec![1u8; 20].into());
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let now = Instant::now();
+        assert_eq!(shared.dials.pop(now), None);
+        for _ in 0..MAX_PEER_CONNECTIONS {
+            shared.file_limit_reached(addr, now);
+        }
+        assert_eq!(shared.connection_limit, MIN_PEER_CONNECTIONS);
+        assert!(!shared.file_limit_reached(addr, now));
+        assert_eq!(shared.dials.pop(now), None);
+        assert_eq!(shared.dials.next_retry(), Some(now + FILE_LIMIT_PAUSE));
+        assert_eq!(shared.dials.pop(now + FILE_LIMIT_PAUSE), Some(addr));
+    }
+
+    #[test]
+    fn test_remote_closed_peer_is_redialed_after_backoff() {
+        let mut shared = Shared::new(vec![1u8; 20].into());
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let now = Instant::now();
+        shared.dials.push(addr, 0.0);
+        assert_eq!(shared.dials.pop(now), Some(addr));
+
+        let backoff = peer_failure::EARLY_DISCONNECT_BACKOFF;
+        for _ in 0..dial_queue::MAX_DIAL_FAILURES {
+            shared.peer_finished(addr, &Ok(DisconnectReason::RemoteClosed), now);
+            assert_eq!(shared.dials.next_retry(), Some(now + backoff));
+            assert_eq!(shared.dials.pop(now), None);
+            // A clean close is no failure, so the wait does not grow and the peer is kept.
+            assert_eq!(shared.dials.pop(now + backoff), Some(addr));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_early_disconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                drop(conn);
+            }
+        });
+
+        let failure = peer_process(test_state(SHORT_TIMEOUTS), addr)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.class, FailureClass::EarlyDisconnect);
+        assert_eq!(
+            failure.reason().retry_backoff(),
+            Some(peer_failure::EARLY_DISCONNECT_BACKOFF)
+        );
+        let handshake_timeout = DisconnectReason::Timeout {
+            phase: peer_failure::Phase::Handshake,
+        };
+        assert!(failure.reason().retry_backoff() < handshake_timeout.retry_backoff());
+    }
+
+    #[tokio::test]
+    async fn test_flooding_peer_is_disconnected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut framed = scripted_peer(&listener, vec![]).await;
+            for i in 0..5000 {
+                let message_type = if i % 2 == 0 {
+                    PeerMessageType::Interested
+                } else {
+                    PeerMessageType::NotInterested
+                };
+                let message = PeerMessage {
+                    message_type,
+                    payload: Bytes::new(),
+                };
+                if framed.feed(message.into()).await.is_err() {
+                    return;
+                }
+            }
+            let _ = framed.flush().await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let state = test_state(SHORT_TIMEOUTS);
+        state.write().await.throttle.exempt_loopback = false;
+        let failure = peer_process(state, addr).await.unwrap_err();
+        assert_eq!(failure.class, FailureClass::ProtocolViolation);
+        assert!(failure.to_string().contains("flood"));
+        // Interest changes are state, so they are let through until the flood ends the
+        // connection, rather than dropped.
+        assert!(failure.to_string().contains("0 messages dropped"));
+    }
+
+    #[tokio::test]
+    async fn test_have_burst_from_seed_is_accepted() {
+        let haves = (0..3000u32)
+            .map(|index| message(PeerMessageType::Have, &index.to_be_bytes()))
+            .collect();
+        let addr = spawn_scripted_peer(haves, false).await;
+        let state = test_state(PeerTimeouts::default());
+        {
+            let mut state = state.write().await;
+            state.throttle.exempt_loopback = false;
+            state.piece_count = Some(3000);
+        }
+        let reason = peer_process(state, addr).await.unwrap();
+        assert_eq!(reason, DisconnectReason::RemoteClosed);
+    }
+
+    fn message(message_type: PeerMessageType, payload: &[u8]) -> PeerMessage {
+        PeerMessage {
+            message_type,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    /// Accepts one connection on `listener`, swaps handshakes for the info hash of
+    /// `test_state` and sends `messages`, returning the open connection.
+    async fn scripted_peer(
+        listener: &tokio::net::TcpListener,
+        messages: Vec<PeerMessage>,
+    ) -> Framed<TcpStream, PeerCodec> {
+        let (conn, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(conn, PeerCodec::new());
+        assert!(matches!(framed.next().await, Some(Ok(PeerFrame::Handshake(_)))));
+        let handshake = Handshake {
+            pstr: BITTORRENT_PROTOCOL.into(),
+            reserved: [0; 8],
+            info_hash: vec![1u8; 20].into(),
+            peer_id: vec![2u8; 20].into(),
+        };
+        framed.send(PeerFrame::Handshake(handshake)).await.unwrap();
+        for m in messages {
+            framed.send(m.into()).await.unwrap();
+        }
+        framed
+    }
+
+    async fn wait_for(state: &RwLock<Shared>, check: impl Fn(&Shared) -> bool) {
+        while !check(&*state.read().await) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    /// Runs `scripted_peer` for one connection in the background. The connection is closed
+    /// once `messages` are sent, or held open for a few seconds if `hold` is set.
+    async fn spawn_scripted_peer(messages: Vec<PeerMessage>, hold: bool) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _framed = scripted_peer(&listener, messages).await;
+            if hold {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_strict_violation_codes() {
+        let cases = [
+            (vec![message(PeerMessageType::Have, &[0, 0, 3])], Violation::BadHaveLength),
+            (vec![message(PeerMessageType::Have, &[0, 0, 0, 13])], Violation::HaveOutOfRange),
+            (vec![message(PeerMessageType::Bitfield, &[0xff])], Violation::BitfieldLength),
+            (
+                vec![message(PeerMessageType::Bitfield, &[0xff, 0xff])],
+                Violation::BitfieldSpareBits,
+            ),
+            (
+                vec![
+                    message(PeerMessageType::Unchoke, &[]),
+                    message(PeerMessageType::Bitfield, &[0xff, 0xf8]),
+                ],
+                Violation::LateBitfield,
+            ),
+            (
+                vec![message(PeerMessageType::Piece, &[0; 9])],
+                Violation::UnsolicitedPiece,
+            ),
+        ];
+        for (messages, expected) in cases {
+            let mut peer_state = PeerState::new(PeerLink::Active(Default::default()), true);
+            let error = messages
+                .into_iter()
+                .find_map(|m| peer_state.apply(m, Some(13)).err())
+                .unwrap();
+            assert_eq!(Violation::find(&error), Some(expected));
+        }
+
+        let unknown = PeerMessage::try_from(peer_codec::Data {
+            message_id: 20,
+            payload: Bytes::new(),
+        })
+        .unwrap_err();
+        assert_eq!(Violation::find(&unknown), Some(Violation::UnknownMessageId));
+
+        // Without strict mode a late Bitfield is tolerated.
+        let mut lenient = PeerState::new(PeerLink::Active(Default::default()), false);
+        lenient.apply(message(PeerMessageType::Unchoke, &[]), Some(13)).unwrap();
+        lenient
+            .apply(message(PeerMessageType::Bitfield, &[0xff, 0xf8]), Some(13))
+            .unwrap();
+        lenient.apply(message(PeerMessageType::Have, &[0, 0, 0, 3]), Some(13)).unwrap();
+        assert_eq!(
+            lenient.capabilities.quirks.iter().collect::<Vec<_>>(),
+            vec![&Quirk::LateBitfield, &Quirk::RedundantHave]
+        );
+    }
+
+    #[test]
+    fn test_hostile_messages_never_panic() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(1200);
+        for strict in [false, true] {
+            for piece_count in [None, Some(13)] {
+                let mut peer_state = PeerState::new(PeerLink::Active(Default::default()), strict);
+                for _ in 0..2000 {
+                    let data = peer_codec::Data {
+                        message_id: rng.gen_range(0..12),
+                        payload: testutil::arbitrary_bytes(&mut rng, 6).into(),
+                    };
+                    if let Ok(message) = PeerMessage::try_from(data) {
+                        let _ = peer_state.apply(message, piece_count);
+                    }
+                }
+                let _ = peer_state.pieces.resolve(13);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict_report_from_scripted_peer() {
+        let messages = vec![
+            message(PeerMessageType::Have, &[0, 0, 0, 1]),
+            message(PeerMessageType::Bitfield, &[0xff]),
+        ];
+        let addr = spawn_scripted_peer(messages, true).await;
+
+        let report = std::env::temp_dir().join(format!("magdl-report-{}.json", rand::random::<u64>()));
+        let state = test_state(PeerTimeouts::default());
+        {
+            let mut state = state.write().await;
+            state.strict = true;
+            state.report_path = Some(report.clone());
+            state.dials.push(addr, 0.0);
+        }
+        dial_peers(Arc::clone(&state)).await;
+
+        let state = state.read().await;
+        assert_eq!(state.violations.count(addr, Violation::LateBitfield), 1);
+        let json = std::fs::read_to_string(&report).unwrap();
+        std::fs::remove_file(&report).unwrap();
+        assert_eq!(json, state.violations.to_json());
+        assert!(json.contains("\"late-bitfield\":1"));
+        assert!(json.contains("\"capabilities\":{\"client\":\"........\",\"reserved\":\"0000000000000000\""));
+    }
+
+    #[tokio::test]
+    async fn test_lenient_mode_keeps_deviating_peers() {
+        // Handshakes with `pstr`, sends an unknown message id and an Unchoke, then holds the
+        // connection until told to close it.
+        async fn deviating_peer(pstr: &'static [u8]) -> (SocketAddr, oneshot::Sender<()>) {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (close, closed) = oneshot::channel();
+            tokio::spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                let mut framed = Framed::new(conn, PeerCodec::new());
+                framed.next().await;
+                let handshake = Handshake {
+                    pstr: Bytes::from_static(pstr),
+                    reserved: [0; 8],
+                    info_hash: vec![1u8; 20].into(),
+                    peer_id: vec![2u8; 20].into(),
+                };
+                framed.send(PeerFrame::Handshake(handshake)).await.unwrap();
+                let unknown = peer_codec::Data {
+                    message_id: 20,
+                    payload: Bytes::from_static(b"x"),
+                };
+                framed.send(PeerFrame::Data(unknown)).await.unwrap();
+                framed.send(message(PeerMessageType::Unchoke, &[]).into()).await.unwrap();
+                let _ = closed.await;
+            });
+            (addr, close)
+        }
+
+        for pstr in [BITTORRENT_PROTOCOL.as_bytes(), b"Another protocol"] {
+            let (addr, close) = deviating_peer(pstr).await;
+            let state = test_state(PeerTimeouts::default());
+            let process = tokio::spawn(peer_process(Arc::clone(&state), addr));
+            let unchoked = |state: &Shared| {
+                let interest = state.peer_state.get(&addr).and_then(|p| p.link.interest());
+                interest.is_some_and(|interest| !interest.am_choked)
+            };
+            wait_for(&state, unchoked).await;
+            let quirks = state.read().await.peer_state[&addr].capabilities.quirks.clone();
+            assert!(quirks.contains(&Quirk::UnknownMessageId));
+            let nonstandard = pstr != BITTORRENT_PROTOCOL.as_bytes();
+            assert_eq!(quirks.contains(&Quirk::NonstandardProtocolString), nonstandard);
+            close.send(()).unwrap();
+            assert_eq!(process.await.unwrap().unwrap(), DisconnectReason::RemoteClosed);
+
+            // The same peer is cut off in strict mode.
+            let (addr, _close) = deviating_peer(pstr).await;
+            let state = test_state(PeerTimeouts::default());
+            state.write().await.strict = true;
+            let failure = peer_process(state, addr).await.unwrap_err();
+            let expected = match nonstandard {
+                true => Violation::BadProtocolString,
+                false => Violation::UnknownMessageId,
+            };
+            assert_eq!(failure.violation, Some(expected));
         }
     }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let addr = spawn_scripted_peer(vec![], true).await;
+
+        let state = test_state(SHORT_TIMEOUTS);
+        let failure = peer_process(Arc::clone(&state), addr).await.unwrap_err();
+        assert_eq!(failure.class, FailureClass::IdleTimeout);
+        assert_eq!(failure.reason().to_string(), "timeout:idle");
+        assert!(state.read().await.peer_state.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_superseded_connection_leaves_newer_state() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = test_state(PeerTimeouts::default());
+        state.write().await.piece_count = Some(13);
+        let has_peer = |generation| {
+            move |state: &Shared| {
+                state.peer_state.get(&addr).is_some_and(|peer| {
+                    peer.generation == generation && peer.pieces.known().is_some()
+                })
+            }
+        };
+        let pieces = |state: &Shared| {
+            let known = state.peer_state[&addr].pieces.known().unwrap();
+            known.iter().filter(|have| **have).count()
+        };
+
+        let first = tokio::spawn(peer_process(Arc::clone(&state), addr));
+        let bitfield = |bits: &[u8]| vec![message(PeerMessageType::Bitfield, bits)];
+        let mut first_peer = scripted_peer(&listener, bitfield(&[0xff, 0xf8])).await;
+        wait_for(&state, has_peer(0)).await;
+        let second = tokio::spawn(peer_process(Arc::clone(&state), addr));
+        let second_peer = scripted_peer(&listener, bitfield(&[0x80, 0x00])).await;
+        wait_for(&state, has_peer(1)).await;
+        assert_eq!(pieces(&*state.read().await), 1);
+
+        // The first connection notices it was superseded on its next message.
+        first_peer
+            .send(message(PeerMessageType::Have, &[0, 0, 0, 3]).into())
+            .await
+            .unwrap();
+        let failure = first.await.unwrap().unwrap_err();
+        assert_eq!(failure.reason(), DisconnectReason::Superseded);
+        {
+            let state = state.read().await;
+            assert_eq!(pieces(&state), 1);
+            assert_eq!(state.peer_state[&addr].generation, 1);
+        }
+
+        drop(second_peer);
+        assert_eq!(second.await.unwrap().unwrap(), DisconnectReason::RemoteClosed);
+        assert!(state.read().await.peer_state.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scripted_disconnect_reasons() {
+        let addr = spawn_scripted_peer(vec![message(PeerMessageType::Unchoke, &[])], false).await;
+        let reason = peer_process(test_state(SHORT_TIMEOUTS), addr).await.unwrap();
+        assert_eq!(reason, DisconnectReason::RemoteClosed);
+
+        let messages = vec![
+            message(PeerMessageType::Have, &[0, 0, 0, 1]),
+            message(PeerMessageType::Bitfield, &[0xff]),
+        ];
+        let addr = spawn_scripted_peer(messages, false).await;
+        let state = test_state(SHORT_TIMEOUTS);
+        state.write().await.strict = true;
+        let failure = peer_process(state, addr).await.unwrap_err();
+        assert_eq!(
+            failure.reason(),
+            DisconnectReason::ProtocolViolation {
+                violation: Some(Violation::LateBitfield)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace_records_and_replays_exchange() {
+        let messages = vec![
+            message(PeerMessageType::Bitfield, &[0b1010_0000]),
+            message(PeerMessageType::Unchoke, &[]),
+            message(PeerMessageType::Have, &[0, 0, 0, 3]),
+            message(PeerMessageType::Interested, &[]),
+        ];
+        let addr = spawn_scripted_peer(messages, false).await;
+
+        let dir = std::env::temp_dir().join(format!("magdl-trace-{}", rand::random::<u64>()));
+        let state = test_state(PeerTimeouts::default());
+        state.write().await.trace = Some(TraceConfig {
+            dir: dir.clone(),
+            full_pieces: false,
+        });
+        peer_process(state, addr).await.unwrap();
+
+        let trace = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let records = testutil::read_trace(&trace).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(records.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+        let outbound = records
+            .iter()
+            .filter(|r| r.direction == peer_trace::Direction::Outbound)
+            .count();
+        assert_eq!(outbound, 1);
+
+        let mut frames = testutil::replay_inbound(&records).unwrap().into_iter();
+        assert!(matches!(frames.next(), Some(PeerFrame::Handshake(_))));
+        let mut peer_state = PeerState::new(PeerLink::Active(Default::default()), false);
+        for frame in frames {
+            let PeerFrame::Data(data) = frame else {
+                panic!("expected data frame");
+            };
+            peer_state
+                .apply(PeerMessage::try_from(data).unwrap(), None)
+                .unwrap();
+        }
+        let interest = peer_state.link.interest().unwrap();
+        assert!(!interest.am_choked);
+        assert!(interest.peer_interested);
+        peer_state.pieces.resolve(8).unwrap();
+        assert!(matches!(
+            peer_state.pieces,
+            PeerPieces::Known(bits) if bits == [true, false, true, true, false, false, false, false]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_writer_coalesces_queued_messages() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for index in 0..10u32 {
+            tx.send(PeerMessage {
+                message_type: PeerMessageType::Have,
+                payload: Bytes::copy_from_slice(&index.to_be_bytes()),
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        let mut sink = FramedWrite::new(CountingWriter::default(), PeerCodec::new());
+        peer_writer(rx, &mut sink).await.unwrap();
+        let writer = sink.get_ref();
+        assert_eq!(writer.writes, 1);
+        assert_eq!(writer.bytes.len(), 10 * 9);
+    }
 }
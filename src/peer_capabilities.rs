@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    peer_codec::{Handshake, BITTORRENT_PROTOCOL},
+    peer_view,
+};
+
+/// Reserved handshake bits: (byte, mask, feature).
+const FEATURE_BITS: [(usize, u8, &str); 3] =
+    [(5, 0x10, "extension"), (7, 0x04, "fast"), (7, 0x01, "dht")];
+
+/// Tolerated deviations seen from a peer. Unlike violations these never end the connection;
+/// they are kept so that reports against a client show how it behaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Quirk {
+    /// Bitfield sent after other messages, outside strict mode.
+    LateBitfield,
+    /// Have for a piece the peer had already announced.
+    RedundantHave,
+    /// Handshake protocol string other than "BitTorrent protocol", outside strict mode.
+    NonstandardProtocolString,
+    /// Message id we do not know, skipped outside strict mode.
+    UnknownMessageId,
+}
+impl Quirk {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Quirk::LateBitfield => "late-bitfield",
+            Quirk::RedundantHave => "redundant-have",
+            Quirk::NonstandardProtocolString => "nonstandard-pstr",
+            Quirk::UnknownMessageId => "unknown-message-id",
+        }
+    }
+}
+
+/// What a peer said about itself in its handshake, and the quirks seen since.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub client: String,
+    pub reserved: [u8; 8],
+    pub quirks: BTreeSet<Quirk>,
+}
+impl Capabilities {
+    pub fn from_handshake(handshake: &Handshake) -> Self {
+        let mut quirks = BTreeSet::new();
+        if handshake.pstr != BITTORRENT_PROTOCOL.as_bytes() {
+            quirks.insert(Quirk::NonstandardProtocolString);
+        }
+        Self {
+            client: peer_view::client_name(&handshake.peer_id),
+            reserved: handshake.reserved,
+            quirks,
+        }
+    }
+    /// Extensions the peer advertises in its reserved bits. None of them are used yet.
+    pub fn features(&self) -> Vec<&'static str> {
+        FEATURE_BITS
+            .iter()
+            .filter(|(byte, mask, _)| self.reserved[*byte] & mask != 0)
+            .map(|(_, _, feature)| *feature)
+            .collect()
+    }
+    /// `{"client":"qBittorrent 4250","reserved":"0000000000100005","features":[...],"quirks":[...]}`
+    pub fn to_json(&self) -> String {
+        let list = |items: Vec<&str>| {
+            items
+                .iter()
+                .map(|item| format!("\"{}\"", item))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let client = self.client.replace('\\', "\\\\").replace('"', "\\\"");
+        format!(
+            "{{\"client\":\"{}\",\"reserved\":\"{}\",\"features\":[{}],\"quirks\":[{}]}}",
+            client,
+            hex::encode(self.reserved),
+            list(self.features()),
+            list(self.quirks.iter().map(Quirk::code).collect())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_capabilities_from_handshake() {
+        let mut capabilities = Capabilities::from_handshake(&Handshake {
+            pstr: BITTORRENT_PROTOCOL.into(),
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0x05],
+            info_hash: Bytes::from_static(&[1; 20]),
+            peer_id: Bytes::from_static(b"-qB4250-abcdefghijkl"),
+        });
+        assert_eq!(capabilities.features(), vec!["extension", "fast", "dht"]);
+        capabilities.quirks.insert(Quirk::RedundantHave);
+        capabilities.quirks.insert(Quirk::LateBitfield);
+        assert_eq!(
+            capabilities.to_json(),
+            "{\"client\":\"qBittorrent 4250\",\"reserved\":\"0000000000100005\",\
+             \"features\":[\"extension\",\"fast\",\"dht\"],\
+             \"quirks\":[\"late-bitfield\",\"redundant-have\"]}"
+        );
+
+        let quoted = Capabilities {
+            client: "a\"b\\".into(),
+            ..Capabilities::default()
+        };
+        assert!(quoted.to_json().starts_with("{\"client\":\"a\\\"b\\\\\","));
+    }
+}
@@ -1,18 +1,47 @@
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
+use crate::{
+    bitfield::MAX_PIECES,
+    peer_trace::{Direction, PeerTrace},
+    violations::Violation,
+    wire_stats::WireStats,
+};
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum PeerFrame {
     Handshake(Handshake),
+    /// A zero-length message, sent only to keep the connection open.
+    KeepAlive,
     Data(Data),
 }
+impl PeerFrame {
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            PeerFrame::Handshake(handshake) => handshake.encode(),
+            PeerFrame::KeepAlive => Bytes::from_static(&[0; 4]),
+            PeerFrame::Data(data) => data.encode(),
+        }
+    }
+    /// Size of the encoded frame.
+    pub fn wire_len(&self) -> usize {
+        match self {
+            PeerFrame::Handshake(handshake) => 1 + handshake.pstr.len() + 8 + 20 + 20,
+            PeerFrame::KeepAlive => 4,
+            PeerFrame::Data(data) => 4 + 1 + data.payload.len(),
+        }
+    }
+}
 
 pub const BITTORRENT_PROTOCOL: &str = "BitTorrent protocol";
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct Handshake {
     pub pstr: Bytes,
+    /// Extension bits, e.g. 0x10 in byte 5 for the extension protocol.
+    pub reserved: [u8; 8],
     pub info_hash: Bytes,
     pub peer_id: Bytes,
 }
@@ -27,26 +56,24 @@ impl Display for Handshake {
     }
 }
 impl Handshake {
+    /// Decodes a handshake with a protocol string of any length. Whether the string is
+    /// `BITTORRENT_PROTOCOL` is left to the caller, which tolerates others outside strict mode.
     fn decode(bytes: &mut BytesMut) -> Result<Option<Self>, std::io::Error> {
-        if bytes.remaining() < 68 {
+        let Some(&pstrlen) = bytes.first() else {
+            return Ok(None);
+        };
+        if bytes.remaining() < 1 + pstrlen as usize + 48 {
             return Ok(None);
         }
-        let backup = bytes.clone();
-        let pstrlen = bytes.get_u8();
-        if pstrlen != 19 {
-            *bytes = backup;
-            return Err(std::io::ErrorKind::Unsupported.into());
-        }
+        bytes.advance(1);
         let pstr = bytes.split_to(pstrlen as usize);
-        if pstr != BITTORRENT_PROTOCOL.as_bytes() {
-            *bytes = backup;
-            return Err(std::io::ErrorKind::Unsupported.into());
-        }
-        bytes.get_int(8);
+        let mut reserved = [0u8; 8];
+        bytes.copy_to_slice(&mut reserved);
         let info_hash = bytes.split_to(20);
         let peer_id = bytes.split_to(20);
         let handshake = Self {
             pstr: pstr.into(),
+            reserved,
             info_hash: info_hash.into(),
             peer_id: peer_id.into(),
         };
@@ -58,41 +85,60 @@ impl Handshake {
         let pstrlen = self.pstr.len() as u8;
         bytes.put_u8(pstrlen);
         bytes.put(self.pstr.clone());
-        bytes.put_int(0, 8);
+        bytes.put_slice(&self.reserved);
         bytes.put(self.info_hash.clone());
         bytes.put(self.peer_id.clone());
         bytes.into()
     }
 }
 
-#[derive(Debug)]
+/// Largest block a Piece message may carry.
+const MAX_BLOCK_BYTES: usize = 16 * 1024;
+/// Largest Bitfield accepted.
+const MAX_BITFIELD_BYTES: usize = MAX_PIECES / 8;
+/// Longest message, after the length prefix, that is buffered; anything longer is rejected
+/// before it is read.
+const MAX_MESSAGE_LEN: usize = if 1 + MAX_BITFIELD_BYTES > 1 + 8 + MAX_BLOCK_BYTES {
+    1 + MAX_BITFIELD_BYTES
+} else {
+    1 + 8 + MAX_BLOCK_BYTES
+};
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Data {
     pub message_id: u8,
     pub payload: Bytes,
 }
 impl Data {
-    fn decode(bytes: &mut BytesMut) -> Result<Option<Self>, std::io::Error> {
-        if bytes.remaining() < 4 {
+    /// Decodes one length-prefixed message, leaving `bytes` untouched until all of it is
+    /// buffered.
+    fn decode(bytes: &mut BytesMut) -> Result<Option<PeerFrame>, std::io::Error> {
+        let Some(prefix) = bytes.get(..4) else {
+            return Ok(None);
+        };
+        let message_len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+        let frame_len = match 4usize.checked_add(message_len) {
+            Some(frame_len) if message_len <= MAX_MESSAGE_LEN => frame_len,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    Violation::MessageTooLong,
+                ))
+            }
+        };
+        if bytes.remaining() < frame_len {
             return Ok(None);
         }
-        let backup = bytes.clone();
-        let message_len = bytes.get_u32();
+        bytes.advance(4);
         if message_len == 0 {
-            return Ok(Some(Self {
-                message_id: 0,
-                payload: Bytes::new(),
-            }));
-        }
-        if bytes.remaining() < message_len as usize {
-            *bytes = backup;
-            return Ok(None);
+            return Ok(Some(PeerFrame::KeepAlive));
         }
         let message_id = bytes.get_u8();
-        let payload = bytes.split_to(message_len as usize - 1);
-        Ok(Some(Self {
+        let payload = bytes.split_to(message_len - 1);
+        Ok(Some(PeerFrame::Data(Self {
             message_id,
             payload: payload.into(),
-        }))
+        })))
     }
     fn encode(&self) -> Bytes {
         let message_len = 1 + self.payload.len();
@@ -104,11 +150,56 @@ impl Data {
     }
 }
 
-pub struct PeerCodec {}
+/// Framing for one connection. The first inbound frame must be the handshake; every frame
+/// after it is a length-prefixed message.
+pub struct PeerCodec {
+    trace: Option<PeerTrace>,
+    stats: Option<Arc<WireStats>>,
+    handshake_received: bool,
+}
 
 impl PeerCodec {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            trace: None,
+            stats: None,
+            handshake_received: false,
+        }
+    }
+    /// Codec that also tees every frame, in both directions, into `trace`.
+    pub fn with_trace(trace: PeerTrace) -> Self {
+        Self {
+            trace: Some(trace),
+            stats: None,
+            handshake_received: false,
+        }
+    }
+    /// Also counts the bytes of every frame, in both directions, into `stats`.
+    pub fn with_stats(self, stats: Arc<WireStats>) -> Self {
+        Self {
+            stats: Some(stats),
+            ..self
+        }
+    }
+    /// Codec for a connection whose handshake has already been read.
+    #[cfg(test)]
+    pub fn established() -> Self {
+        Self {
+            trace: None,
+            stats: None,
+            handshake_received: true,
+        }
+    }
+    fn record(&mut self, direction: Direction, frame: &PeerFrame) {
+        if let Some(stats) = &self.stats {
+            stats.record(direction, frame);
+        }
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.record(direction, frame) {
+                println!("Disabling peer trace: {}", e);
+                self.trace = None;
+            }
+        }
     }
 }
 
@@ -116,12 +207,22 @@ impl Decoder for PeerCodec {
     type Item = PeerFrame;
     type Error = std::io::Error;
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if let Some(data) = Data::decode(buf)? {
-            return Ok(Some(PeerFrame::Data(data)));
-        } else if let Some(handshake) = Handshake::decode(buf)? {
-            return Ok(Some(PeerFrame::Handshake(handshake)));
-        }
-        Ok(None)
+        let frame = if self.handshake_received {
+            match Data::decode(buf)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            }
+        } else {
+            match Handshake::decode(buf)? {
+                Some(handshake) => {
+                    self.handshake_received = true;
+                    PeerFrame::Handshake(handshake)
+                }
+                None => return Ok(None),
+            }
+        };
+        self.record(Direction::Inbound, &frame);
+        Ok(Some(frame))
     }
 
     fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
@@ -131,10 +232,7 @@ impl Decoder for PeerCodec {
                 if buf.is_empty() {
                     Ok(None)
                 } else {
-                    Err(
-                        std::io::Error::new(std::io::ErrorKind::Other, "bytes remaining on stream")
-                            .into(),
-                    )
+                    Err(std::io::Error::other("bytes remaining on stream"))
                 }
             }
         }
@@ -151,10 +249,8 @@ impl Encoder<PeerFrame> for PeerCodec {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: PeerFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        match item {
-            PeerFrame::Data(i) => dst.put(i.encode()),
-            PeerFrame::Handshake(i) => dst.put(i.encode()),
-        };
+        self.record(Direction::Outbound, &item);
+        dst.put(item.to_bytes());
         Ok(())
     }
 }
@@ -179,13 +275,25 @@ mod tests {
             assert_eq!(hs.info_hash, info_hash);
             assert_eq!(hs.peer_id, peer_id);
         } else {
-            assert!(false);
+            panic!("expected handshake frame");
         }
+
+        // Another protocol string is decoded as is; its length decides where the rest starts.
+        let mut bytes = BytesMut::from(&[4u8, b'a', b'b', b'c', b'd'][..]);
+        bytes.put_bytes(7, 48);
+        match PeerCodec::new().decode(&mut bytes).unwrap().unwrap() {
+            PeerFrame::Handshake(hs) => {
+                assert_eq!(hs.pstr, &b"abcd"[..]);
+                assert_eq!(hs.peer_id, vec![7u8; 20]);
+            }
+            frame => panic!("expected handshake frame, got {:?}", frame),
+        }
+        assert!(bytes.is_empty());
     }
 
     #[test]
     fn test_decode_data() {
-        let mut codec = PeerCodec::new();
+        let mut codec = PeerCodec::established();
         let mut bytes = BytesMut::new();
         bytes.put_u32(20);
         bytes.put_u8(5);
@@ -195,7 +303,190 @@ mod tests {
             assert_eq!(d.message_id, 5);
             assert_eq!(d.payload, vec![1u8; 19]);
         } else {
-            assert!(false);
+            panic!("expected data frame");
+        }
+    }
+
+    #[test]
+    fn test_oversized_length_prefix_rejected() {
+        let mut codec = PeerCodec::established();
+        let mut bytes = BytesMut::new();
+        bytes.put_u32(MAX_MESSAGE_LEN as u32);
+        assert_eq!(codec.decode(&mut bytes).unwrap(), None);
+
+        let mut bytes = BytesMut::new();
+        bytes.put_u32(MAX_MESSAGE_LEN as u32 + 1);
+        let error = codec.decode(&mut bytes).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let mut bytes = BytesMut::from(&[0xff; 4][..]);
+        let error = anyhow::Error::new(codec.decode(&mut bytes).unwrap_err());
+        assert_eq!(Violation::find(&error), Some(Violation::MessageTooLong));
+    }
+
+    fn handshake(reserved: [u8; 8]) -> PeerFrame {
+        PeerFrame::Handshake(Handshake {
+            pstr: BITTORRENT_PROTOCOL.into(),
+            reserved,
+            info_hash: Bytes::from_static(&[0xaa; 20]),
+            peer_id: Bytes::from_static(b"-WM0001-123456789012"),
+        })
+    }
+
+    fn data(message_id: u8, payload: &'static [u8]) -> PeerFrame {
+        PeerFrame::Data(Data {
+            message_id,
+            payload: Bytes::from_static(payload),
+        })
+    }
+
+    /// Every message from the spec with its exact wire bytes.
+    fn message_vectors() -> Vec<(PeerFrame, Vec<u8>)> {
+        vec![
+            (PeerFrame::KeepAlive, vec![0, 0, 0, 0]),
+            (data(0, b""), vec![0, 0, 0, 1, 0]),
+            (data(1, b""), vec![0, 0, 0, 1, 1]),
+            (data(2, b""), vec![0, 0, 0, 1, 2]),
+            (data(3, b""), vec![0, 0, 0, 1, 3]),
+            (data(4, &[0, 0, 0, 42]), vec![0, 0, 0, 5, 4, 0, 0, 0, 42]),
+            (data(5, &[0xff, 0x80]), vec![0, 0, 0, 3, 5, 0xff, 0x80]),
+            (
+                data(6, &[0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0]),
+                vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (
+                data(7, &[0, 0, 0, 1, 0, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef]),
+                vec![0, 0, 0, 13, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef],
+            ),
+            (
+                data(8, &[0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0]),
+                vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (data(9, &[0x1a, 0xe1]), vec![0, 0, 0, 3, 9, 0x1a, 0xe1]),
+        ]
+    }
+
+    fn handshake_bytes(reserved: [u8; 8]) -> Vec<u8> {
+        let mut bytes = vec![19];
+        bytes.extend_from_slice(b"BitTorrent protocol");
+        bytes.extend_from_slice(&reserved);
+        bytes.extend_from_slice(&[0xaa; 20]);
+        bytes.extend_from_slice(b"-WM0001-123456789012");
+        bytes
+    }
+
+    const RESERVED_PATTERNS: [[u8; 8]; 4] = [
+        [0; 8],
+        // Extension protocol.
+        [0, 0, 0, 0, 0, 0x10, 0, 0],
+        // DHT and fast extension.
+        [0, 0, 0, 0, 0, 0, 0, 0x05],
+        [0xff; 8],
+    ];
+
+    #[test]
+    fn test_spec_vectors() {
+        for reserved in RESERVED_PATTERNS {
+            let bytes = handshake_bytes(reserved);
+            assert_eq!(bytes.len(), 68);
+            assert_eq!(handshake(reserved).to_bytes(), bytes);
+            let mut buf = BytesMut::from(&bytes[..]);
+            assert_eq!(PeerCodec::new().decode(&mut buf).unwrap(), Some(handshake(reserved)));
+            assert!(buf.is_empty());
+        }
+        for (frame, bytes) in message_vectors() {
+            assert_eq!(frame.to_bytes(), bytes, "{:?}", frame);
+            let mut buf = BytesMut::from(&bytes[..]);
+            let decoded = PeerCodec::established().decode(&mut buf).unwrap();
+            assert_eq!(decoded.as_ref(), Some(&frame));
+            assert!(buf.is_empty(), "{:?}", frame);
+        }
+    }
+
+    /// Decodes `input` fed `chunk_len` bytes at a time, as a socket read might deliver it.
+    fn decode_chunked(input: &[u8], chunk_len: usize) -> Vec<PeerFrame> {
+        let mut codec = PeerCodec::new();
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        for chunk in input.chunks(chunk_len) {
+            buf.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+        if let Some(frame) = codec.decode_eof(&mut buf).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_chunked_decoding_matches_whole() {
+        let mut input = handshake_bytes(RESERVED_PATTERNS[1]);
+        let mut expected = vec![handshake(RESERVED_PATTERNS[1])];
+        for (frame, bytes) in message_vectors() {
+            input.extend_from_slice(&bytes);
+            expected.push(frame);
+        }
+        // A block-sized Piece, long enough to straddle many reads.
+        let mut piece = vec![0, 0, 0x40, 9, 7, 0, 0, 0, 2, 0, 0, 0, 0];
+        piece.extend((0..0x4000).map(|i| i as u8));
+        input.extend_from_slice(&piece);
+        expected.push(PeerFrame::Data(Data {
+            message_id: 7,
+            payload: Bytes::copy_from_slice(&piece[5..]),
+        }));
+
+        for chunk_len in (1..=80).chain([500, 4096, input.len()]) {
+            assert_eq!(decode_chunked(&input, chunk_len), expected, "chunks of {}", chunk_len);
+        }
+    }
+
+    /// Feeds `input` to a fresh decoder in random-sized chunks until it errors or runs dry.
+    fn decode_in_chunks(rng: &mut impl rand::Rng, input: &[u8]) {
+        let mut codec = PeerCodec::new();
+        let mut buf = BytesMut::new();
+        let mut rest = input;
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at(rng.gen_range(1..=rest.len()));
+            rest = tail;
+            buf.extend_from_slice(chunk);
+            loop {
+                match codec.decode(&mut buf) {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(_) => return,
+                }
+            }
+        }
+        let _ = codec.decode_eof(&mut buf);
+    }
+
+    #[test]
+    fn test_hostile_streams_never_panic() {
+        use crate::testutil::{arbitrary_bytes, corrupt};
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let handshake = Handshake {
+            pstr: BITTORRENT_PROTOCOL.into(),
+            reserved: [0; 8],
+            info_hash: vec![1u8; 20].into(),
+            peer_id: vec![2u8; 20].into(),
+        };
+        let mut valid = handshake.encode().to_vec();
+        for (message_id, payload) in [(5u8, vec![0xff; 3]), (4, vec![0, 0, 0, 7]), (1, vec![])] {
+            let data = Data {
+                message_id,
+                payload: payload.into(),
+            };
+            valid.extend_from_slice(&data.encode());
+        }
+
+        let mut rng = StdRng::seed_from_u64(1200);
+        for _ in 0..5000 {
+            let corrupted = corrupt(&mut rng, &valid);
+            decode_in_chunks(&mut rng, &corrupted);
+            let random = arbitrary_bytes(&mut rng, 300);
+            decode_in_chunks(&mut rng, &random);
         }
     }
 }
@@ -0,0 +1,491 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    time::Duration,
+};
+
+use crate::{peer_link::BrokenInvariant, violations::Violation};
+
+/// Number of most recent dial outcomes considered when looking for a dominant failure.
+pub const FAILURE_WINDOW: usize = 50;
+/// Fraction of the window a single failure key must account for to raise a warning.
+pub const DOMINANCE_THRESHOLD: f64 = 0.9;
+/// Retry delay for a peer that accepted and then dropped us; it exists but was busy.
+pub const EARLY_DISCONNECT_BACKOFF: Duration = Duration::from_secs(15);
+/// Retry delay for a peer that refused, timed out or errored.
+pub const UNREACHABLE_BACKOFF: Duration = Duration::from_secs(600);
+/// "Too many open files", for the process and for the whole system. std has no `ErrorKind` for
+/// these, so they are matched by raw errno.
+#[cfg(unix)]
+const FILE_LIMIT_ERRNOS: &[i32] = &[libc::EMFILE, libc::ENFILE];
+#[cfg(not(unix))]
+const FILE_LIMIT_ERRNOS: &[i32] = &[];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailureClass {
+    ConnectRefused,
+    ConnectTimeout,
+    Handshake,
+    HandshakeTimeout,
+    /// Reset or closed by the remote while we were handshaking.
+    EarlyDisconnect,
+    IdleTimeout,
+    ProtocolViolation,
+    /// A newer connection to the same address took over.
+    Superseded,
+    /// We ran out of file descriptors; nothing to do with the peer.
+    FileLimit,
+    /// Our side of the connection broke a `PeerLink` invariant.
+    BrokenInvariant,
+    Io,
+}
+impl Display for FailureClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FailureClass::ConnectRefused => "connect refused",
+            FailureClass::ConnectTimeout => "connect timeout",
+            FailureClass::Handshake => "handshake failure",
+            FailureClass::HandshakeTimeout => "handshake timeout",
+            FailureClass::EarlyDisconnect => "early disconnect",
+            FailureClass::IdleTimeout => "idle timeout",
+            FailureClass::ProtocolViolation => "protocol violation",
+            FailureClass::Superseded => "superseded",
+            FailureClass::FileLimit => "out of file descriptors",
+            FailureClass::BrokenInvariant => "broken invariant",
+            FailureClass::Io => "io error",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Connection phase a timeout fired in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Connect,
+    Handshake,
+    Idle,
+}
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Phase::Connect => "connect",
+            Phase::Handshake => "handshake",
+            Phase::Idle => "idle",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why a peer connection ended. Its Display form is a stable, machine-readable code such as
+/// `timeout:idle` or `protocol-violation:late-bitfield`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer closed the connection after the handshake.
+    RemoteClosed,
+    ConnectRefused,
+    Timeout {
+        phase: Phase,
+    },
+    /// Reset or closed by the remote while we were handshaking.
+    EarlyDisconnect,
+    /// Wrong info hash, or some other frame where the handshake should be.
+    BadHandshake,
+    /// `violation` is set when the rule broken has a violation code.
+    ProtocolViolation {
+        violation: Option<Violation>,
+    },
+    /// A newer connection to the same address took over.
+    Superseded,
+    FileLimit,
+    BrokenInvariant,
+    IoError {
+        kind: std::io::ErrorKind,
+    },
+}
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::RemoteClosed => f.write_str("remote-closed"),
+            DisconnectReason::ConnectRefused => f.write_str("connect-refused"),
+            DisconnectReason::Timeout { phase } => write!(f, "timeout:{}", phase),
+            DisconnectReason::EarlyDisconnect => f.write_str("early-disconnect"),
+            DisconnectReason::BadHandshake => f.write_str("bad-handshake"),
+            DisconnectReason::ProtocolViolation { violation: None } => {
+                f.write_str("protocol-violation")
+            }
+            DisconnectReason::ProtocolViolation {
+                violation: Some(violation),
+            } => write!(f, "protocol-violation:{}", violation.code()),
+            DisconnectReason::Superseded => f.write_str("superseded"),
+            DisconnectReason::FileLimit => f.write_str("file-limit"),
+            DisconnectReason::BrokenInvariant => f.write_str("broken-invariant"),
+            DisconnectReason::IoError { kind } => write!(f, "io-error:{:?}", kind),
+        }
+    }
+}
+impl DisconnectReason {
+    /// Delay before the address is worth dialing again, or `None` if it should not be retried.
+    pub fn retry_backoff(&self) -> Option<Duration> {
+        match self {
+            // It exists but was busy, or had nothing more for us.
+            DisconnectReason::RemoteClosed | DisconnectReason::EarlyDisconnect => {
+                Some(EARLY_DISCONNECT_BACKOFF)
+            }
+            DisconnectReason::ConnectRefused
+            | DisconnectReason::Timeout { .. }
+            | DisconnectReason::IoError { .. } => Some(UNREACHABLE_BACKOFF),
+            // The address is still connected through the newer connection.
+            DisconnectReason::Superseded => None,
+            // Not the address's fault; the dialer queues it again itself.
+            DisconnectReason::FileLimit => None,
+            // Our bug, which a new connection would only run into again.
+            DisconnectReason::BrokenInvariant => None,
+            DisconnectReason::BadHandshake | DisconnectReason::ProtocolViolation { .. } => None,
+        }
+    }
+}
+
+/// Raised when a message arrives on a connection whose address a newer connection has taken
+/// over.
+#[derive(Debug)]
+pub struct Superseded;
+impl Display for Superseded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Superseded by a newer connection")
+    }
+}
+impl std::error::Error for Superseded {}
+
+/// Class plus OS error number, so that e.g. EPERM and ECONNRESET io errors are kept apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FailureKey {
+    pub class: FailureClass,
+    pub errno: Option<i32>,
+}
+impl Display for FailureKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.errno {
+            Some(errno) => write!(f, "{} (os error {})", self.class, errno),
+            None => write!(f, "{}", self.class),
+        }
+    }
+}
+
+/// Structured error returned by a peer task.
+#[derive(Debug)]
+pub struct PeerFailure {
+    pub class: FailureClass,
+    pub errno: Option<i32>,
+    /// Set when the peer broke a protocol rule that has a violation code.
+    pub violation: Option<Violation>,
+    pub error: anyhow::Error,
+}
+impl Display for PeerFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:#}", self.class, self.error)
+    }
+}
+impl PeerFailure {
+    pub fn new(class: FailureClass, error: impl Into<anyhow::Error>) -> Self {
+        Self::with_errno(class, None, error.into())
+    }
+    fn with_errno(class: FailureClass, errno: Option<i32>, error: anyhow::Error) -> Self {
+        let class = match errno {
+            Some(errno) if FILE_LIMIT_ERRNOS.contains(&errno) => FailureClass::FileLimit,
+            _ if error.is::<BrokenInvariant>() => FailureClass::BrokenInvariant,
+            _ => class,
+        };
+        Self {
+            class,
+            errno,
+            violation: Violation::find(&error),
+            error,
+        }
+    }
+    pub fn connect(error: std::io::Error) -> Self {
+        let class = match error.kind() {
+            std::io::ErrorKind::ConnectionRefused => FailureClass::ConnectRefused,
+            std::io::ErrorKind::TimedOut => FailureClass::ConnectTimeout,
+            _ => FailureClass::Io,
+        };
+        Self::with_errno(class, error.raw_os_error(), error.into())
+    }
+    pub fn io(error: std::io::Error) -> Self {
+        let class = match error.kind() {
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::Unsupported => {
+                FailureClass::ProtocolViolation
+            }
+            _ => FailureClass::Io,
+        };
+        Self::with_errno(class, error.raw_os_error(), error.into())
+    }
+    /// Like `io`, but a reset, broken pipe or EOF counts as an early disconnect.
+    pub fn handshake_io(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof => Self::with_errno(
+                FailureClass::EarlyDisconnect,
+                error.raw_os_error(),
+                error.into(),
+            ),
+            _ => Self::io(error),
+        }
+    }
+    pub fn reason(&self) -> DisconnectReason {
+        match self.class {
+            FailureClass::ConnectRefused => DisconnectReason::ConnectRefused,
+            FailureClass::ConnectTimeout => DisconnectReason::Timeout {
+                phase: Phase::Connect,
+            },
+            FailureClass::HandshakeTimeout => DisconnectReason::Timeout {
+                phase: Phase::Handshake,
+            },
+            FailureClass::IdleTimeout => DisconnectReason::Timeout { phase: Phase::Idle },
+            FailureClass::EarlyDisconnect => DisconnectReason::EarlyDisconnect,
+            FailureClass::Handshake => DisconnectReason::BadHandshake,
+            FailureClass::ProtocolViolation => DisconnectReason::ProtocolViolation {
+                violation: self.violation,
+            },
+            FailureClass::Superseded => DisconnectReason::Superseded,
+            FailureClass::FileLimit => DisconnectReason::FileLimit,
+            FailureClass::BrokenInvariant => DisconnectReason::BrokenInvariant,
+            FailureClass::Io => DisconnectReason::IoError {
+                kind: self
+                    .error
+                    .downcast_ref::<std::io::Error>()
+                    .map_or(std::io::ErrorKind::Other, std::io::Error::kind),
+            },
+        }
+    }
+    pub fn key(&self) -> FailureKey {
+        FailureKey {
+            class: self.class,
+            errno: self.errno,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DominantFailure {
+    pub key: FailureKey,
+    pub count: usize,
+    pub window: usize,
+}
+impl Display for DominantFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of the last {} peer connections failed with {}",
+            self.count, self.window, self.key
+        )
+    }
+}
+
+/// Rolling record of peer task outcomes.
+pub struct FailureStats {
+    totals: HashMap<FailureKey, usize>,
+    successes: usize,
+    recent: VecDeque<Option<FailureKey>>,
+    warned: bool,
+}
+impl Default for FailureStats {
+    fn default() -> Self {
+        Self {
+            totals: HashMap::new(),
+            successes: 0,
+            recent: VecDeque::with_capacity(FAILURE_WINDOW),
+            warned: false,
+        }
+    }
+}
+impl FailureStats {
+    /// Records the outcome of one peer task (`None` on success). Returns the dominant failure
+    /// the first time a single key crosses the threshold; it is reported again only after
+    /// dominance has lapsed.
+    pub fn record(&mut self, outcome: Option<FailureKey>) -> Option<DominantFailure> {
+        match outcome {
+            Some(key) => *self.totals.entry(key).or_insert(0) += 1,
+            None => self.successes += 1,
+        }
+        if self.recent.len() == FAILURE_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(outcome);
+
+        match self.dominant() {
+            Some(dominant) if !self.warned => {
+                self.warned = true;
+                Some(dominant)
+            }
+            Some(_) => None,
+            None => {
+                self.warned = false;
+                None
+            }
+        }
+    }
+    pub fn dominant(&self) -> Option<DominantFailure> {
+        if self.recent.len() < FAILURE_WINDOW {
+            return None;
+        }
+        let mut counts = HashMap::new();
+        for key in self.recent.iter().flatten() {
+            *counts.entry(*key).or_insert(0usize) += 1;
+        }
+        let (key, count) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+        if count as f64 > self.recent.len() as f64 * DOMINANCE_THRESHOLD {
+            Some(DominantFailure {
+                key,
+                count,
+                window: self.recent.len(),
+            })
+        } else {
+            None
+        }
+    }
+}
+impl Display for FailureStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut totals = self.totals.iter().collect::<Vec<_>>();
+        totals.sort_by(|a, b| b.1.cmp(a.1));
+        write!(f, "Peer connections: {} ok", self.successes)?;
+        for (key, count) in totals {
+            write!(f, ", {} {}", count, key)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer_link::PeerLink, peer_message::PeerMessageType};
+
+    const EPERM: FailureKey = FailureKey {
+        class: FailureClass::Io,
+        errno: Some(1),
+    };
+
+    #[test]
+    fn test_systematic_failure_warns_once() {
+        let mut stats = FailureStats::default();
+        let mut warnings = Vec::new();
+        for _ in 0..FAILURE_WINDOW * 2 {
+            if let Some(warning) = stats.record(Some(EPERM)) {
+                warnings.push(warning);
+            }
+        }
+        assert_eq!(
+            warnings,
+            vec![DominantFailure {
+                key: EPERM,
+                count: FAILURE_WINDOW,
+                window: FAILURE_WINDOW,
+            }]
+        );
+        assert_eq!(stats.totals[&EPERM], FAILURE_WINDOW * 2);
+    }
+
+    #[test]
+    fn test_mixed_outcomes_do_not_warn() {
+        let mut stats = FailureStats::default();
+        let timeout = FailureKey {
+            class: FailureClass::ConnectTimeout,
+            errno: None,
+        };
+        for i in 0..FAILURE_WINDOW * 2 {
+            let outcome = match i % 3 {
+                0 => Some(EPERM),
+                1 => Some(timeout),
+                _ => None,
+            };
+            assert_eq!(stats.record(outcome), None);
+        }
+    }
+
+    #[test]
+    fn test_warning_rearms_after_recovery() {
+        let mut stats = FailureStats::default();
+        let fired = (0..FAILURE_WINDOW)
+            .filter_map(|_| stats.record(Some(EPERM)))
+            .count();
+        assert_eq!(fired, 1);
+        for _ in 0..FAILURE_WINDOW {
+            stats.record(None);
+        }
+        let fired = (0..FAILURE_WINDOW)
+            .filter_map(|_| stats.record(Some(EPERM)))
+            .count();
+        assert_eq!(fired, 1);
+    }
+
+    #[test]
+    fn test_connect_error_classification() {
+        let refused = PeerFailure::connect(std::io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(refused.class, FailureClass::ConnectRefused);
+        let eperm = PeerFailure::connect(std::io::Error::from_raw_os_error(1));
+        assert_eq!(eperm.key(), EPERM);
+        let bad_frame = PeerFailure::io(std::io::ErrorKind::Unsupported.into());
+        assert_eq!(bad_frame.class, FailureClass::ProtocolViolation);
+        let reset = PeerFailure::handshake_io(std::io::Error::from_raw_os_error(104));
+        assert_eq!(reset.class, FailureClass::EarlyDisconnect);
+        assert_eq!(reset.errno, Some(104));
+        let pipe = PeerFailure::handshake_io(std::io::ErrorKind::BrokenPipe.into());
+        assert_eq!(pipe.class, FailureClass::EarlyDisconnect);
+        let reset_later = PeerFailure::io(std::io::ErrorKind::ConnectionReset.into());
+        assert_eq!(reset_later.class, FailureClass::Io);
+        for &errno in FILE_LIMIT_ERRNOS {
+            let exhausted = PeerFailure::connect(std::io::Error::from_raw_os_error(errno));
+            assert_eq!(exhausted.class, FailureClass::FileLimit);
+            assert_eq!(exhausted.reason().to_string(), "file-limit");
+            assert_eq!(exhausted.errno, Some(errno));
+        }
+    }
+
+    #[test]
+    fn test_broken_invariant_is_its_own_reason() {
+        let broken = BrokenInvariant {
+            message: PeerMessageType::Request,
+            state: PeerLink::Connecting,
+        };
+        let failure = PeerFailure::new(FailureClass::ProtocolViolation, broken);
+        assert_eq!(failure.class, FailureClass::BrokenInvariant);
+        assert_eq!(failure.reason().to_string(), "broken-invariant");
+        assert_eq!(failure.reason().retry_backoff(), None);
+    }
+
+    #[test]
+    fn test_early_disconnect_retries_sooner() {
+        let early = DisconnectReason::EarlyDisconnect.retry_backoff().unwrap();
+        let timeout = DisconnectReason::Timeout {
+            phase: Phase::Connect,
+        };
+        assert!(early < timeout.retry_backoff().unwrap());
+        let violation = DisconnectReason::ProtocolViolation { violation: None };
+        assert_eq!(violation.retry_backoff(), None);
+    }
+
+    #[test]
+    fn test_disconnect_reasons() {
+        let reset = PeerFailure::io(std::io::ErrorKind::ConnectionReset.into());
+        assert_eq!(
+            reset.reason(),
+            DisconnectReason::IoError {
+                kind: std::io::ErrorKind::ConnectionReset
+            }
+        );
+        assert_eq!(reset.reason().to_string(), "io-error:ConnectionReset");
+        let refused = PeerFailure::connect(std::io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(refused.reason(), DisconnectReason::ConnectRefused);
+        let late = PeerFailure::new(
+            FailureClass::ProtocolViolation,
+            Violation::LateBitfield.error("after Unchoke".into()),
+        );
+        assert_eq!(
+            late.reason().to_string(),
+            "protocol-violation:late-bitfield"
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use std::fmt::Display;
+
+use crate::peer_message::PeerMessageType;
+
+/// Choke and interest flags of an active connection, for both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest {
+    /// We are choking the peer.
+    pub choking: bool,
+    /// The peer is interested in our pieces.
+    pub peer_interested: bool,
+    /// The peer is choking us.
+    pub am_choked: bool,
+    /// We are interested in the peer's pieces.
+    pub am_interested: bool,
+}
+impl Default for Interest {
+    fn default() -> Self {
+        Self {
+            choking: true,
+            peer_interested: false,
+            am_choked: true,
+            am_interested: false,
+        }
+    }
+}
+
+/// Lifecycle of one peer connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerLink {
+    Connecting,
+    Handshaking,
+    Active(Interest),
+    Disconnecting,
+}
+
+/// Our side's events are not produced yet: interest and requests wait on the piece picker.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    Connected,
+    HandshakeReceived,
+    /// A message from the peer.
+    Received(PeerMessageType),
+    /// We want to become (un)interested in the peer.
+    SetInterested(bool),
+    /// We want to (un)choke the peer.
+    SetChoking(bool),
+    /// We want to request a block.
+    Request,
+    Disconnect,
+}
+
+/// A transition emitted a message that the state it led to does not permit. This is a bug on
+/// our side rather than the peer's.
+#[derive(Debug)]
+pub struct BrokenInvariant {
+    pub message: PeerMessageType,
+    pub state: PeerLink,
+}
+impl Display for BrokenInvariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} emitted while {:?}", self.message, self.state)
+    }
+}
+impl std::error::Error for BrokenInvariant {}
+
+impl PeerLink {
+    /// Applies an event and returns the message, if any, that must be sent to the peer as a
+    /// result. Events that are not valid in the current state leave it unchanged and fail.
+    /// With `check_invariants`, and always in debug builds, a message that the resulting state
+    /// does not permit also leaves it unchanged and fails with `BrokenInvariant`.
+    pub fn on(
+        &mut self,
+        event: LinkEvent,
+        check_invariants: bool,
+    ) -> anyhow::Result<Option<PeerMessageType>> {
+        let (next, emit) = match (*self, event) {
+            (_, LinkEvent::Disconnect) => (PeerLink::Disconnecting, None),
+            (PeerLink::Connecting, LinkEvent::Connected) => (PeerLink::Handshaking, None),
+            (PeerLink::Handshaking, LinkEvent::HandshakeReceived) => {
+                (PeerLink::Active(Interest::default()), None)
+            }
+            (PeerLink::Active(mut interest), LinkEvent::Received(message_type)) => {
+                match message_type {
+                    PeerMessageType::Choke => interest.am_choked = true,
+                    PeerMessageType::Unchoke => interest.am_choked = false,
+                    PeerMessageType::Interested => interest.peer_interested = true,
+                    PeerMessageType::NotInterested => interest.peer_interested = false,
+                    _ => {}
+                }
+                (PeerLink::Active(interest), None)
+            }
+            (PeerLink::Active(mut interest), LinkEvent::SetInterested(interested)) => {
+                let emit = match (interest.am_interested, interested) {
+                    (false, true) => Some(PeerMessageType::Interested),
+                    (true, false) => Some(PeerMessageType::NotInterested),
+                    _ => None,
+                };
+                interest.am_interested = interested;
+                (PeerLink::Active(interest), emit)
+            }
+            (PeerLink::Active(mut interest), LinkEvent::SetChoking(choking)) => {
+                let emit = match (interest.choking, choking) {
+                    (false, true) => Some(PeerMessageType::Choke),
+                    (true, false) => Some(PeerMessageType::Unchoke),
+                    _ => None,
+                };
+                interest.choking = choking;
+                (PeerLink::Active(interest), emit)
+            }
+            (PeerLink::Active(interest), LinkEvent::Request) => {
+                if interest.am_choked || !interest.am_interested {
+                    anyhow::bail!("Cannot request while {:?}", interest);
+                }
+                (PeerLink::Active(interest), Some(PeerMessageType::Request))
+            }
+            (state, event) => anyhow::bail!("{:?} is not valid while {:?}", event, state),
+        };
+        if check_invariants || cfg!(debug_assertions) {
+            next.check(emit)?;
+        }
+        *self = next;
+        Ok(emit)
+    }
+    fn check(&self, message: Option<PeerMessageType>) -> Result<(), BrokenInvariant> {
+        match message {
+            Some(message) if !self.permits(message) => Err(BrokenInvariant {
+                message,
+                state: *self,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Whether sending `message` is consistent with the current state.
+    fn permits(&self, message: PeerMessageType) -> bool {
+        match (self, message) {
+            (PeerLink::Active(interest), PeerMessageType::Request) => {
+                !interest.am_choked && interest.am_interested
+            }
+            (PeerLink::Active(_), _) => true,
+            _ => false,
+        }
+    }
+    pub fn interest(&self) -> Option<Interest> {
+        match self {
+            PeerLink::Active(interest) => Some(*interest),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_TYPES: [PeerMessageType; 10] = [
+        PeerMessageType::Choke,
+        PeerMessageType::Unchoke,
+        PeerMessageType::Interested,
+        PeerMessageType::NotInterested,
+        PeerMessageType::Have,
+        PeerMessageType::Bitfield,
+        PeerMessageType::Request,
+        PeerMessageType::Piece,
+        PeerMessageType::Cancel,
+        PeerMessageType::Port,
+    ];
+
+    fn all_events() -> Vec<LinkEvent> {
+        let mut events = vec![
+            LinkEvent::Connected,
+            LinkEvent::HandshakeReceived,
+            LinkEvent::SetInterested(false),
+            LinkEvent::SetInterested(true),
+            LinkEvent::SetChoking(false),
+            LinkEvent::SetChoking(true),
+            LinkEvent::Request,
+            LinkEvent::Disconnect,
+        ];
+        events.extend(ALL_TYPES.map(LinkEvent::Received));
+        events
+    }
+
+    fn all_states() -> Vec<PeerLink> {
+        let mut states = vec![
+            PeerLink::Connecting,
+            PeerLink::Handshaking,
+            PeerLink::Disconnecting,
+        ];
+        for bits in 0..16u8 {
+            states.push(PeerLink::Active(Interest {
+                choking: bits & 1 != 0,
+                peer_interested: bits & 2 != 0,
+                am_choked: bits & 4 != 0,
+                am_interested: bits & 8 != 0,
+            }));
+        }
+        states
+    }
+
+    /// Expected outcome of every event in every state, written out independently of `on`.
+    fn expected(state: PeerLink, event: LinkEvent) -> Option<(PeerLink, Option<PeerMessageType>)> {
+        use LinkEvent::*;
+        use PeerMessageType as T;
+        if event == Disconnect {
+            return Some((PeerLink::Disconnecting, None));
+        }
+        let i = match (state, event) {
+            (PeerLink::Connecting, Connected) => return Some((PeerLink::Handshaking, None)),
+            (PeerLink::Handshaking, HandshakeReceived) => {
+                return Some((PeerLink::Active(Interest::default()), None))
+            }
+            (PeerLink::Active(i), _) => i,
+            _ => return None,
+        };
+        let active = PeerLink::Active;
+        Some(match event {
+            Received(T::Choke) => (
+                active(Interest {
+                    am_choked: true,
+                    ..i
+                }),
+                None,
+            ),
+            Received(T::Unchoke) => (
+                active(Interest {
+                    am_choked: false,
+                    ..i
+                }),
+                None,
+            ),
+            Received(T::Interested) => (
+                active(Interest {
+                    peer_interested: true,
+                    ..i
+                }),
+                None,
+            ),
+            Received(T::NotInterested) => (
+                active(Interest {
+                    peer_interested: false,
+                    ..i
+                }),
+                None,
+            ),
+            Received(_) => (state, None),
+            SetInterested(true) if !i.am_interested => (
+                active(Interest {
+                    am_interested: true,
+                    ..i
+                }),
+                Some(T::Interested),
+            ),
+            SetInterested(false) if i.am_interested => (
+                active(Interest {
+                    am_interested: false,
+                    ..i
+                }),
+                Some(T::NotInterested),
+            ),
+            SetInterested(_) => (state, None),
+            SetChoking(true) if !i.choking => {
+                (active(Interest { choking: true, ..i }), Some(T::Choke))
+            }
+            SetChoking(false) if i.choking => (
+                active(Interest {
+                    choking: false,
+                    ..i
+                }),
+                Some(T::Unchoke),
+            ),
+            SetChoking(_) => (state, None),
+            Request if !i.am_choked && i.am_interested => (state, Some(T::Request)),
+            _ => return None,
+        })
+    }
+
+    #[test]
+    fn test_transition_table() {
+        for state in all_states() {
+            for event in all_events() {
+                let mut link = state;
+                let result = link.on(event, true);
+                match expected(state, event) {
+                    Some((next, emit)) => {
+                        assert_eq!(result.unwrap(), emit, "{:?} in {:?}", event, state);
+                        assert_eq!(link, next, "{:?} in {:?}", event, state);
+                    }
+                    None => {
+                        assert!(result.is_err(), "{:?} in {:?} should fail", event, state);
+                        assert_eq!(link, state);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_request_only_when_unchoked_and_interested() {
+        let mut link = PeerLink::Connecting;
+        link.on(LinkEvent::Connected, true).unwrap();
+        link.on(LinkEvent::HandshakeReceived, true).unwrap();
+        assert!(link.on(LinkEvent::Request, true).is_err());
+        assert_eq!(
+            link.on(LinkEvent::SetInterested(true), true).unwrap(),
+            Some(PeerMessageType::Interested)
+        );
+        assert_eq!(link.on(LinkEvent::SetInterested(true), true).unwrap(), None);
+        assert!(link.on(LinkEvent::Request, true).is_err());
+        link.on(LinkEvent::Received(PeerMessageType::Unchoke), true)
+            .unwrap();
+        assert_eq!(
+            link.on(LinkEvent::Request, true).unwrap(),
+            Some(PeerMessageType::Request)
+        );
+        link.on(LinkEvent::Received(PeerMessageType::Choke), true)
+            .unwrap();
+        assert!(link.on(LinkEvent::Request, true).is_err());
+    }
+
+    #[test]
+    fn test_message_not_permitted_breaks_invariant() {
+        let request = Some(PeerMessageType::Request);
+        let broken = PeerLink::Connecting.check(request).unwrap_err();
+        assert_eq!(broken.to_string(), "Request emitted while Connecting");
+        let choked = PeerLink::Active(Interest {
+            am_interested: true,
+            ..Interest::default()
+        });
+        assert!(choked.check(request).is_err());
+        assert!(choked.check(Some(PeerMessageType::Interested)).is_ok());
+        assert!(PeerLink::Disconnecting.check(None).is_ok());
+    }
+}
@@ -1,6 +1,11 @@
 use bytes::Bytes;
 
-#[derive(Debug, Eq, PartialEq)]
+use crate::{
+    peer_codec::{Data, PeerFrame},
+    violations::Violation,
+};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum PeerMessageType {
     Choke,
     Unchoke,
@@ -26,13 +31,14 @@ impl PeerMessageType {
             PeerMessageType::Piece => 7,
             PeerMessageType::Cancel => 8,
             PeerMessageType::Port => 9,
-            _ => panic!("Invalid peer message type"),
         }
     }
 }
-impl From<u8> for PeerMessageType {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for PeerMessageType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
             0 => Self::Choke,
             1 => Self::Unchoke,
             2 => Self::Interested,
@@ -43,8 +49,11 @@ impl From<u8> for PeerMessageType {
             7 => Self::Piece,
             8 => Self::Cancel,
             9 => Self::Port,
-            _ => panic!("Invalid peer message type"),
-        }
+            _ => {
+                let detail = format!("Unknown peer message id {}", value);
+                return Err(Violation::UnknownMessageId.error(detail));
+            }
+        })
     }
 }
 
@@ -53,3 +62,68 @@ pub struct PeerMessage {
     pub message_type: PeerMessageType,
     pub payload: Bytes,
 }
+impl TryFrom<Data> for PeerMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(data: Data) -> Result<Self, Self::Error> {
+        Ok(Self {
+            message_type: PeerMessageType::try_from(data.message_id)?,
+            payload: data.payload,
+        })
+    }
+}
+impl From<PeerMessage> for PeerFrame {
+    fn from(message: PeerMessage) -> Self {
+        PeerFrame::Data(Data {
+            message_id: message.message_type.raw_value(),
+            payload: message.payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_codec::PeerCodec;
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn test_message_id_round_trip() {
+        for id in 0..=9u8 {
+            assert_eq!(PeerMessageType::try_from(id).unwrap().raw_value(), id);
+        }
+        for id in 10..=255u8 {
+            assert!(PeerMessageType::try_from(id).is_err());
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip_through_codec() {
+        let mut codec = PeerCodec::established();
+        for id in 0..=9u8 {
+            let message = PeerMessage {
+                message_type: PeerMessageType::try_from(id).unwrap(),
+                payload: Bytes::from(vec![id; id as usize]),
+            };
+            let mut buf = BytesMut::new();
+            codec.encode(message.into(), &mut buf).unwrap();
+            let Some(PeerFrame::Data(data)) = codec.decode(&mut buf).unwrap() else {
+                panic!("expected data frame");
+            };
+            let decoded = PeerMessage::try_from(data).unwrap();
+            assert_eq!(decoded.message_type.raw_value(), id);
+            assert_eq!(decoded.payload, vec![id; id as usize]);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_unknown_message_id_is_an_error() {
+        let data = Data {
+            message_id: 20,
+            payload: Bytes::new(),
+        };
+        assert!(PeerMessage::try_from(data).is_err());
+    }
+}
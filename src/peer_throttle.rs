@@ -0,0 +1,273 @@
+use std::{net::SocketAddr, time::Instant};
+
+use crate::{bitfield::MAX_PIECES, peer_message::PeerMessageType};
+
+/// Sustained rate and burst size of one token bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// Inbound message budgets applied in each peer task before a message reaches shared state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThrottleConfig {
+    pub enabled: bool,
+    /// Skip throttling for peers on a loopback address, e.g. local test seeds.
+    pub exempt_loopback: bool,
+    /// Choke, Unchoke, Interested, NotInterested, Bitfield and Port.
+    pub control: Limit,
+    /// The burst is raised to the piece count, so that a peer may announce every piece once.
+    pub have: Limit,
+    /// Request and Cancel.
+    pub request: Limit,
+    pub piece: Limit,
+    /// Messages over their budget drain this bucket; once it is empty the peer is
+    /// disconnected. Only Request, Cancel and Piece are dropped, the rest are let through.
+    pub drops: Limit,
+}
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            exempt_loopback: true,
+            control: Limit {
+                per_second: 5.0,
+                burst: 20.0,
+            },
+            have: Limit {
+                per_second: 200.0,
+                burst: 2000.0,
+            },
+            request: Limit {
+                per_second: 100.0,
+                burst: 500.0,
+            },
+            piece: Limit {
+                per_second: 2000.0,
+                burst: 4000.0,
+            },
+            drops: Limit {
+                per_second: 10.0,
+                burst: 500.0,
+            },
+        }
+    }
+}
+
+/// Token bucket refilled from elapsed time on each use rather than by a timer.
+#[derive(Debug)]
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+impl TokenBucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Drop,
+    /// The peer kept flooding after going over its budget.
+    Disconnect,
+}
+
+/// Per-peer message budgets.
+#[derive(Debug)]
+pub struct PeerThrottle {
+    control: TokenBucket,
+    have: TokenBucket,
+    request: TokenBucket,
+    piece: TokenBucket,
+    drops: TokenBucket,
+    pub dropped: usize,
+}
+impl PeerThrottle {
+    /// `None` when throttling is disabled for this peer. Until `piece_count` is known the Have
+    /// burst allows for the largest torrent accepted.
+    pub fn for_peer(
+        config: &ThrottleConfig,
+        addr: SocketAddr,
+        piece_count: Option<usize>,
+        now: Instant,
+    ) -> Option<Self> {
+        if !config.enabled || (config.exempt_loopback && addr.ip().is_loopback()) {
+            return None;
+        }
+        let have = Limit {
+            burst: config
+                .have
+                .burst
+                .max(piece_count.unwrap_or(MAX_PIECES) as f64),
+            ..config.have
+        };
+        Some(Self {
+            control: TokenBucket::new(config.control, now),
+            have: TokenBucket::new(have, now),
+            request: TokenBucket::new(config.request, now),
+            piece: TokenBucket::new(config.piece, now),
+            drops: TokenBucket::new(config.drops, now),
+            dropped: 0,
+        })
+    }
+    /// Messages that change what we know about the peer, such as Unchoke or Have, are never
+    /// dropped, since skipping one would leave our view of the peer wrong for the rest of the
+    /// connection; over their budget they are let through while the drop budget lasts.
+    pub fn check(&mut self, message_type: &PeerMessageType, now: Instant) -> Verdict {
+        let bucket = match message_type {
+            PeerMessageType::Have => &mut self.have,
+            PeerMessageType::Request | PeerMessageType::Cancel => &mut self.request,
+            PeerMessageType::Piece => &mut self.piece,
+            PeerMessageType::Choke
+            | PeerMessageType::Unchoke
+            | PeerMessageType::Interested
+            | PeerMessageType::NotInterested
+            | PeerMessageType::Bitfield
+            | PeerMessageType::Port => &mut self.control,
+        };
+        if bucket.take(now) {
+            return Verdict::Accept;
+        }
+        if !self.drops.take(now) {
+            return Verdict::Disconnect;
+        }
+        let droppable = matches!(
+            message_type,
+            PeerMessageType::Request | PeerMessageType::Cancel | PeerMessageType::Piece
+        );
+        if !droppable {
+            return Verdict::Accept;
+        }
+        self.dropped += 1;
+        Verdict::Drop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn remote() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 6881))
+    }
+
+    #[test]
+    fn test_flood_is_bounded_then_cut_off() {
+        let config = ThrottleConfig::default();
+        let start = Instant::now();
+        let mut throttle = PeerThrottle::for_peer(&config, remote(), None, start).unwrap();
+
+        // A second of Request spam at 10k messages per second.
+        let mut accepted = 0;
+        let mut verdict = Verdict::Accept;
+        for i in 0..10_000u32 {
+            let now = start + Duration::from_micros(i as u64 * 100);
+            verdict = throttle.check(&PeerMessageType::Request, now);
+            match verdict {
+                Verdict::Accept => accepted += 1,
+                Verdict::Drop => {}
+                Verdict::Disconnect => break,
+            }
+        }
+        assert_eq!(verdict, Verdict::Disconnect);
+        // Burst plus at most a second of refill reached the coordinator.
+        assert!(accepted as f64 <= config.request.burst + config.request.per_second);
+        assert!(throttle.dropped >= config.drops.burst as usize);
+    }
+
+    #[test]
+    fn test_state_messages_are_never_dropped() {
+        let config = ThrottleConfig::default();
+        let start = Instant::now();
+        let mut throttle = PeerThrottle::for_peer(&config, remote(), Some(13), start).unwrap();
+        let tolerated = (config.control.burst + config.drops.burst) as usize;
+        for _ in 0..tolerated {
+            assert_eq!(
+                throttle.check(&PeerMessageType::Interested, start),
+                Verdict::Accept
+            );
+        }
+        assert_eq!(
+            throttle.check(&PeerMessageType::Interested, start),
+            Verdict::Disconnect
+        );
+        assert_eq!(throttle.dropped, 0);
+    }
+
+    #[test]
+    fn test_have_burst_covers_every_piece() {
+        let config = ThrottleConfig::default();
+        let start = Instant::now();
+        for piece_count in [Some(3000), None] {
+            let mut throttle =
+                PeerThrottle::for_peer(&config, remote(), piece_count, start).unwrap();
+            for _ in 0..3000 {
+                assert_eq!(
+                    throttle.check(&PeerMessageType::Have, start),
+                    Verdict::Accept
+                );
+            }
+            assert_eq!(throttle.dropped, 0);
+        }
+    }
+
+    #[test]
+    fn test_buckets_refill_over_time() {
+        let config = ThrottleConfig::default();
+        let start = Instant::now();
+        let mut throttle = PeerThrottle::for_peer(&config, remote(), None, start).unwrap();
+        for _ in 0..config.request.burst as usize {
+            assert_eq!(
+                throttle.check(&PeerMessageType::Request, start),
+                Verdict::Accept
+            );
+        }
+        assert_eq!(
+            throttle.check(&PeerMessageType::Cancel, start),
+            Verdict::Drop
+        );
+        // Other message types have their own budget.
+        assert_eq!(
+            throttle.check(&PeerMessageType::Piece, start),
+            Verdict::Accept
+        );
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            throttle.check(&PeerMessageType::Request, later),
+            Verdict::Accept
+        );
+    }
+
+    #[test]
+    fn test_exemptions() {
+        let now = Instant::now();
+        let config = ThrottleConfig::default();
+        let loopback = SocketAddr::from(([127, 0, 0, 1], 6881));
+        assert!(PeerThrottle::for_peer(&config, loopback, None, now).is_none());
+        let disabled = ThrottleConfig {
+            enabled: false,
+            ..ThrottleConfig::default()
+        };
+        assert!(PeerThrottle::for_peer(&disabled, remote(), None, now).is_none());
+    }
+}
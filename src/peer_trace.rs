@@ -0,0 +1,117 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::peer_codec::{Data, PeerFrame};
+use crate::peer_message::PeerMessageType;
+
+/// Bytes of a Piece payload kept when payloads are truncated: the index and begin fields.
+const TRUNCATED_PIECE_BYTES: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct TraceConfig {
+    pub dir: PathBuf,
+    /// Keep Piece block data in the trace instead of only its index and offset.
+    pub full_pieces: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound = 0,
+    Outbound = 1,
+}
+
+/// Per-peer log of every frame crossing the codec. Each record is
+/// `[direction: u8][micros since start: u64][length: u32][frame bytes]`, big endian.
+pub struct PeerTrace {
+    out: BufWriter<File>,
+    started: Instant,
+    full_pieces: bool,
+}
+impl PeerTrace {
+    /// Starts a new trace file for a connection to `addr`. Earlier traces of the same address,
+    /// such as the one of a connection that failed before a redial, are never overwritten.
+    pub fn create(config: &TraceConfig, addr: SocketAddr) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        let path = trace_path(&config.dir, addr, SystemTime::now());
+        let file = File::options().write(true).create_new(true).open(path)?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            started: Instant::now(),
+            full_pieces: config.full_pieces,
+        })
+    }
+    pub fn record(&mut self, direction: Direction, frame: &PeerFrame) -> std::io::Result<()> {
+        let bytes = match frame {
+            PeerFrame::Data(data)
+                if !self.full_pieces
+                    && data.message_id == PeerMessageType::Piece.raw_value()
+                    && data.payload.len() > TRUNCATED_PIECE_BYTES =>
+            {
+                PeerFrame::Data(Data {
+                    message_id: data.message_id,
+                    payload: data.payload.slice(..TRUNCATED_PIECE_BYTES),
+                })
+                .to_bytes()
+            }
+            frame => frame.to_bytes(),
+        };
+        self.out.write_u8(direction as u8)?;
+        self.out
+            .write_u64::<BigEndian>(self.started.elapsed().as_micros() as u64)?;
+        self.out.write_u32::<BigEndian>(bytes.len() as u32)?;
+        self.out.write_all(&bytes)?;
+        // Flushed per frame so a trace survives the crash it is meant to explain.
+        self.out.flush()
+    }
+}
+
+/// Trace file for a connection to `addr` started at `started`, e.g.
+/// `10.0.0.1_6881-1700000000123456.trace`.
+pub fn trace_path(dir: &Path, addr: SocketAddr, started: SystemTime) -> PathBuf {
+    let micros = started
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros());
+    dir.join(format!(
+        "{}-{}.trace",
+        addr.to_string().replace([':', '[', ']'], "_"),
+        micros
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_traces_of_one_address_are_kept() {
+        let dir = std::env::temp_dir().join(format!("magdl-trace-{}", rand::random::<u64>()));
+        let config = TraceConfig {
+            dir: dir.clone(),
+            full_pieces: false,
+        };
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        for _ in 0..2 {
+            let mut trace = PeerTrace::create(&config, addr).unwrap();
+            trace
+                .record(Direction::Inbound, &PeerFrame::KeepAlive)
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let traces = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| testutil::read_trace(&entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(traces.len(), 2);
+        assert!(traces.iter().all(|records| records.len() == 1));
+    }
+}
@@ -0,0 +1,264 @@
+use std::{fmt::Write, net::SocketAddr, time::Duration};
+
+use crate::{peer_capabilities::Capabilities, peer_link::Interest, wire_stats::WireTotals};
+
+/// Rows shown before the rest are summarised in a footer.
+pub const PEER_VIEW_ROWS: usize = 20;
+
+/// Snapshot of one connected peer, taken under the state lock so that rendering never has to
+/// look at live peers.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    pub capabilities: Capabilities,
+    /// `None` until the handshake completes.
+    pub interest: Option<Interest>,
+    /// Fraction of pieces the peer has, once the piece count is known.
+    pub progress: Option<f64>,
+    /// Bytes received from the peer, counted by its codec.
+    pub inbound: WireTotals,
+    /// Bytes sent to the peer, counted by its codec.
+    pub outbound: WireTotals,
+    pub age: Duration,
+}
+impl PeerStats {
+    /// Average rate of block data received since the connection was made, in bytes per second.
+    pub fn down_rate(&self) -> f64 {
+        self.inbound.payload as f64 / self.age.as_secs_f64().max(1.0)
+    }
+    /// Average rate of block data sent since the connection was made, in bytes per second.
+    pub fn up_rate(&self) -> f64 {
+        self.outbound.payload as f64 / self.age.as_secs_f64().max(1.0)
+    }
+    /// `c` we choke them, `i` they are interested, `C` they choke us, `I` we are interested,
+    /// `S` they have every piece.
+    fn flags(&self) -> String {
+        let Some(interest) = self.interest else {
+            return "-----".to_string();
+        };
+        [
+            (interest.choking, 'c'),
+            (interest.peer_interested, 'i'),
+            (interest.am_choked, 'C'),
+            (interest.am_interested, 'I'),
+            (self.progress == Some(1.0), 'S'),
+        ]
+        .iter()
+        .map(|&(set, flag)| if set { flag } else { '-' })
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSort {
+    Rate,
+    Progress,
+    Age,
+}
+impl std::str::FromStr for PeerSort {
+    type Err = anyhow::Error;
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "rate" => Ok(PeerSort::Rate),
+            "progress" => Ok(PeerSort::Progress),
+            "age" => Ok(PeerSort::Age),
+            _ => anyhow::bail!(
+                "Unknown peer sort {}, expected rate, progress or age",
+                value
+            ),
+        }
+    }
+}
+
+/// Client name from an Azureus-style peer id (`-qB4250-...`), else the printable prefix.
+pub fn client_name(peer_id: &[u8]) -> String {
+    if let [b'-', a, b, v @ .., b'-'] = peer_id.get(..8).unwrap_or_default() {
+        if a.is_ascii_alphanumeric()
+            && b.is_ascii_alphanumeric()
+            && v.iter().all(u8::is_ascii_alphanumeric)
+        {
+            let id = [*a, *b];
+            let name = match &id {
+                b"qB" => "qBittorrent",
+                b"TR" => "Transmission",
+                b"UT" => "uTorrent",
+                b"DE" => "Deluge",
+                b"lt" => "libtorrent",
+                b"LT" => "libtorrent",
+                b"AZ" => "Vuze",
+                b"WM" => "magdl",
+                _ => {
+                    return format!(
+                        "{} {}",
+                        String::from_utf8_lossy(&id),
+                        String::from_utf8_lossy(v)
+                    )
+                }
+            };
+            return format!("{} {}", name, String::from_utf8_lossy(v));
+        }
+    }
+    peer_id
+        .iter()
+        .take(8)
+        .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+        .collect()
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    if secs < 3600 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    }
+}
+
+fn format_rate(bytes_per_sec: f64) -> String {
+    if bytes_per_sec < 1024.0 {
+        format!("{:.0} B/s", bytes_per_sec)
+    } else if bytes_per_sec < 1024.0 * 1024.0 {
+        format!("{:.1} KiB/s", bytes_per_sec / 1024.0)
+    } else {
+        format!("{:.1} MiB/s", bytes_per_sec / (1024.0 * 1024.0))
+    }
+}
+
+/// Renders `stats` as a plain-text table, best first by `sort`, with at most `max_rows` rows.
+pub fn render(stats: &[PeerStats], sort: PeerSort, max_rows: usize) -> String {
+    let mut sorted = stats.iter().collect::<Vec<_>>();
+    match sort {
+        PeerSort::Rate => sorted.sort_by(|a, b| b.down_rate().total_cmp(&a.down_rate())),
+        PeerSort::Progress => sorted.sort_by(|a, b| {
+            let progress = |s: &PeerStats| s.progress.unwrap_or(-1.0);
+            progress(b).total_cmp(&progress(a))
+        }),
+        PeerSort::Age => sorted.sort_by_key(|peer| std::cmp::Reverse(peer.age)),
+    }
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<22} {:<18} {:<5} {:>11} {:>11} {:>5} {:>7}",
+        "ADDRESS", "CLIENT", "FLAGS", "DOWN", "UP", "HAVE", "AGE"
+    )
+    .unwrap();
+    for peer in sorted.iter().take(max_rows) {
+        let progress = match peer.progress {
+            Some(progress) => format!("{:.0}%", progress * 100.0),
+            None => "?".to_string(),
+        };
+        writeln!(
+            out,
+            "{:<22} {:<18} {:<5} {:>11} {:>11} {:>5} {:>7}",
+            peer.addr.to_string(),
+            peer.capabilities
+                .client
+                .chars()
+                .take(18)
+                .collect::<String>(),
+            peer.flags(),
+            format_rate(peer.down_rate()),
+            format_rate(peer.up_rate()),
+            progress,
+            format_age(peer.age)
+        )
+        .unwrap();
+    }
+    if sorted.len() > max_rows {
+        let rest = &sorted[max_rows..];
+        let down = rest.iter().map(|peer| peer.down_rate()).sum::<f64>();
+        let up = rest.iter().map(|peer| peer.up_rate()).sum::<f64>();
+        writeln!(
+            out,
+            "... and {} more peers, {} down and {} up combined",
+            rest.len(),
+            format_rate(down),
+            format_rate(up)
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(port: u16, received_bytes: u64, progress: Option<f64>, age_secs: u64) -> PeerStats {
+        // Protocol overhead is left out of the rates.
+        let overhead = 1024 * 1024;
+        PeerStats {
+            addr: SocketAddr::from(([10, 0, 0, 1], port)),
+            capabilities: Capabilities {
+                client: client_name(b"-qB4250-abcdefghijkl"),
+                ..Capabilities::default()
+            },
+            interest: Some(Interest {
+                am_choked: port.is_multiple_of(2),
+                ..Interest::default()
+            }),
+            progress,
+            inbound: WireTotals {
+                payload: received_bytes,
+                overhead,
+            },
+            outbound: WireTotals {
+                payload: received_bytes / 4,
+                overhead,
+            },
+            age: Duration::from_secs(age_secs),
+        }
+    }
+
+    fn peers() -> Vec<PeerStats> {
+        let mut connecting = stats(4, 0, None, 0);
+        connecting.capabilities.client = client_name(&[0xff, b'x', 0, 0, 0, 0, 0, 0]);
+        connecting.interest = None;
+        vec![
+            stats(1, 10 * 1024, Some(0.5), 10),
+            stats(2, 30 * 1024 * 1024, Some(1.0), 3),
+            stats(3, 512, Some(0.25), 4000),
+            connecting,
+        ]
+    }
+
+    #[test]
+    fn test_render_by_rate() {
+        assert_eq!(
+            render(&peers(), PeerSort::Rate, PEER_VIEW_ROWS),
+            "\
+ADDRESS                CLIENT             FLAGS        DOWN          UP  HAVE     AGE
+10.0.0.1:2             qBittorrent 4250   c-C-S  10.0 MiB/s   2.5 MiB/s  100%   0m03s
+10.0.0.1:1             qBittorrent 4250   c----   1.0 KiB/s     256 B/s   50%   0m10s
+10.0.0.1:3             qBittorrent 4250   c----       0 B/s       0 B/s   25%   1h06m
+10.0.0.1:4             .x......           -----       0 B/s       0 B/s     ?   0m00s
+"
+        );
+    }
+
+    #[test]
+    fn test_render_by_progress_with_footer() {
+        assert_eq!(
+            render(&peers(), PeerSort::Progress, 2),
+            "\
+ADDRESS                CLIENT             FLAGS        DOWN          UP  HAVE     AGE
+10.0.0.1:2             qBittorrent 4250   c-C-S  10.0 MiB/s   2.5 MiB/s  100%   0m03s
+10.0.0.1:1             qBittorrent 4250   c----   1.0 KiB/s     256 B/s   50%   0m10s
+... and 2 more peers, 0 B/s down and 0 B/s up combined
+"
+        );
+        assert_eq!(
+            render(&[], PeerSort::Age, 2),
+            "ADDRESS                CLIENT             FLAGS        DOWN          UP  HAVE     AGE\n"
+        );
+    }
+
+    #[test]
+    fn test_client_name() {
+        assert_eq!(client_name(b"-TR2940-abcdefghijkl"), "Transmission 2940");
+        assert_eq!(client_name(b"-XX0001-abcdefghijkl"), "XX 0001");
+        assert_eq!(client_name(b"M7-2-2--abcdefghijkl"), "M7-2-2--");
+        assert_eq!(client_name(b""), "");
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+/// How long a successful lookup is reused. The system resolver does not report record TTLs.
+pub const POSITIVE_TTL: Duration = Duration::from_secs(300);
+/// How long a failed lookup is reused; doubled for each consecutive failure.
+pub const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+pub const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(600);
+
+/// A host lookup, so tests can substitute their own.
+pub trait Lookup: Send + Sync {
+    fn lookup(&self, host: &str, port: u16)
+        -> BoxFuture<'static, std::io::Result<Vec<SocketAddr>>>;
+}
+
+/// Runs a blocking lookup function on tokio's blocking pool so it cannot stall other tasks.
+pub struct BlockingLookup<F>(pub F);
+impl<F> Lookup for BlockingLookup<F>
+where
+    F: Fn(&str, u16) -> std::io::Result<Vec<SocketAddr>> + Clone + Send + Sync + 'static,
+{
+    fn lookup(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, std::io::Result<Vec<SocketAddr>>> {
+        let lookup = self.0.clone();
+        let host = host.to_string();
+        async move {
+            tokio::task::spawn_blocking(move || lookup(&host, port))
+                .await
+                .map_err(std::io::Error::other)?
+        }
+        .boxed()
+    }
+}
+
+fn system_lookup(host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+type PendingLookup = Shared<BoxFuture<'static, Result<Vec<SocketAddr>, String>>>;
+
+enum Entry {
+    Resolved {
+        addrs: Vec<SocketAddr>,
+        expires: Instant,
+    },
+    Failed {
+        error: String,
+        failures: u32,
+        expires: Instant,
+    },
+    /// A lookup in progress; concurrent callers wait on the same one.
+    Pending {
+        lookup: PendingLookup,
+        failures: u32,
+    },
+}
+
+/// Caching, deduplicating host resolver shared by everything that dials hostnames.
+pub struct Resolver {
+    lookup: Arc<dyn Lookup>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    cache: Mutex<HashMap<(String, u16), Entry>>,
+}
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver")
+            .field("positive_ttl", &self.positive_ttl)
+            .field("negative_ttl", &self.negative_ttl)
+            .finish_non_exhaustive()
+    }
+}
+impl Resolver {
+    pub fn new(lookup: Arc<dyn Lookup>) -> Self {
+        Self {
+            lookup,
+            positive_ttl: POSITIVE_TTL,
+            negative_ttl: NEGATIVE_TTL,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn system() -> Self {
+        Self::new(Arc::new(BlockingLookup(system_lookup)))
+    }
+    pub async fn resolve(&self, host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        let key = (host.to_string(), port);
+        let lookup = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(Entry::Pending { lookup, .. }) = cache.get(&key) {
+                lookup.clone()
+            } else {
+                let now = Instant::now();
+                let failures = match cache.get(&key) {
+                    Some(Entry::Resolved { addrs, expires }) if *expires > now => {
+                        return Ok(addrs.clone());
+                    }
+                    Some(Entry::Failed {
+                        error,
+                        failures,
+                        expires,
+                    }) => {
+                        if *expires > now {
+                            anyhow::bail!("{}", error);
+                        }
+                        *failures
+                    }
+                    _ => 0,
+                };
+                let lookup = self.start(host, port);
+                cache.insert(
+                    key.clone(),
+                    Entry::Pending {
+                        lookup: lookup.clone(),
+                        failures,
+                    },
+                );
+                lookup
+            }
+        };
+
+        let result = lookup.await;
+        let mut cache = self.cache.lock().unwrap();
+        // The first waiter to finish records the outcome for everyone.
+        if let Some(Entry::Pending { failures, .. }) = cache.get(&key) {
+            let failures = *failures;
+            let entry = match &result {
+                Ok(addrs) => Entry::Resolved {
+                    addrs: addrs.clone(),
+                    expires: Instant::now() + self.positive_ttl,
+                },
+                Err(error) => Entry::Failed {
+                    error: error.clone(),
+                    failures: failures + 1,
+                    expires: Instant::now()
+                        + (self.negative_ttl * 2u32.pow(failures.min(10))).min(MAX_NEGATIVE_TTL),
+                },
+            };
+            cache.insert(key, entry);
+        }
+        result.map_err(|e| anyhow::anyhow!(e))
+    }
+    fn start(&self, host: &str, port: u16) -> PendingLookup {
+        let host = host.to_string();
+        self.lookup
+            .lookup(&host, port)
+            .map(move |result| match result {
+                Ok(addrs) if addrs.is_empty() => Err(format!("{} resolved to no addresses", host)),
+                Ok(addrs) => Ok(addrs),
+                Err(e) => Err(format!("Failed to resolve {}: {}", host, e)),
+            })
+            .boxed()
+            .shared()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 6969))
+    }
+
+    /// Resolver whose lookups sleep for `delay` on the blocking pool and count their calls.
+    fn counting(delay: Duration, result: Option<SocketAddr>) -> (Resolver, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let lookup = BlockingLookup(move |_: &str, _: u16| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(delay);
+            result
+                .map(|addr| vec![addr])
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        });
+        (Resolver::new(Arc::new(lookup)), calls)
+    }
+
+    #[tokio::test]
+    async fn test_repeated_and_concurrent_lookups_share_one_call() {
+        let (resolver, calls) = counting(Duration::from_millis(50), Some(addr()));
+        let concurrent = (0..5)
+            .map(|_| resolver.resolve("tracker.example", 6969))
+            .collect::<Vec<_>>();
+        for result in futures::future::join_all(concurrent).await {
+            assert_eq!(result.unwrap(), vec![addr()]);
+        }
+        assert_eq!(
+            resolver.resolve("tracker.example", 6969).await.unwrap(),
+            vec![addr()]
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        resolver.resolve("tracker.example", 1337).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_cached_with_backoff() {
+        let (mut resolver, calls) = counting(Duration::ZERO, None);
+        resolver.negative_ttl = Duration::from_millis(40);
+        assert!(resolver.resolve("nxdomain.example", 80).await.is_err());
+        assert!(resolver.resolve("nxdomain.example", 80).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(resolver.resolve("nxdomain.example", 80).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // The second failure is cached for twice as long.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(resolver.resolve("nxdomain.example", 80).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_slow_lookup_does_not_block_runtime() {
+        let (resolver, _) = counting(Duration::from_millis(300), Some(addr()));
+        let started = Instant::now();
+        let ticker = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            started.elapsed()
+        });
+        resolver.resolve("slow.example", 80).await.unwrap();
+        assert!(ticker.await.unwrap() < Duration::from_millis(200));
+    }
+}
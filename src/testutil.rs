@@ -0,0 +1,87 @@
+//! Helpers shared by tests: reading peer traces back and replaying them through the decoder,
+//! and random inputs for the decoder robustness tests.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+    time::Duration,
+};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{Bytes, BytesMut};
+use rand::Rng;
+use tokio_util::codec::Decoder;
+
+use crate::peer_codec::{PeerCodec, PeerFrame};
+use crate::peer_trace::Direction;
+
+#[derive(Debug)]
+pub struct TraceRecord {
+    pub direction: Direction,
+    pub elapsed: Duration,
+    pub bytes: Bytes,
+}
+
+pub fn read_trace(path: &Path) -> std::io::Result<Vec<TraceRecord>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    loop {
+        let direction = match input.read_u8() {
+            Ok(0) => Direction::Inbound,
+            Ok(1) => Direction::Outbound,
+            Ok(_) => return Err(std::io::ErrorKind::InvalidData.into()),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        };
+        let elapsed = Duration::from_micros(input.read_u64::<BigEndian>()?);
+        let mut bytes = vec![0u8; input.read_u32::<BigEndian>()? as usize];
+        input.read_exact(&mut bytes)?;
+        records.push(TraceRecord {
+            direction,
+            elapsed,
+            bytes: bytes.into(),
+        });
+    }
+}
+
+/// Feeds the inbound side of a trace through a fresh decoder, returning the frames it yields.
+pub fn replay_inbound(records: &[TraceRecord]) -> std::io::Result<Vec<PeerFrame>> {
+    let mut codec = PeerCodec::new();
+    let mut buf = BytesMut::new();
+    let mut frames = Vec::new();
+    for record in records.iter().filter(|r| r.direction == Direction::Inbound) {
+        buf.extend_from_slice(&record.bytes);
+        while let Some(frame) = codec.decode(&mut buf)? {
+            frames.push(frame);
+        }
+    }
+    if let Some(frame) = codec.decode_eof(&mut buf)? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Up to `max_len` uniformly random bytes.
+pub fn arbitrary_bytes(rng: &mut impl Rng, max_len: usize) -> Vec<u8> {
+    let len = rng.gen_range(0..=max_len);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// `valid` with a few random byte flips, insertions, deletions or a truncation applied, so that
+/// inputs stay close enough to the real format to get past the first checks.
+pub fn corrupt(rng: &mut impl Rng, valid: &[u8]) -> Vec<u8> {
+    let mut bytes = valid.to_vec();
+    for _ in 0..rng.gen_range(1..=4) {
+        let at = rng.gen_range(0..=bytes.len());
+        match rng.gen_range(0..4) {
+            0 if at < bytes.len() => bytes[at] = rng.gen(),
+            1 => bytes.insert(at, rng.gen()),
+            2 if at < bytes.len() => {
+                bytes.remove(at);
+            }
+            _ => bytes.truncate(at),
+        }
+    }
+    bytes
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use url::Url;
+
+use crate::tracker_stream::normalize_tracker_url;
+
+/// First line of a history file, naming its format version.
+const HEADER: &str = "magdl-tracker-history 1";
+/// Age at which a recorded score counts for half.
+pub const SCORE_HALF_LIFE: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Records not updated for this long are dropped on load.
+pub const MAX_RECORD_AGE: Duration = Duration::from_secs(90 * 24 * 3600);
+/// How long a tracker that failed in its last session is held back; doubled for each further
+/// consecutive failure.
+pub const FAILURE_COOLDOWN: Duration = Duration::from_secs(3600);
+/// A failing tracker is still tried normally at least this often.
+pub const MAX_FAILURE_COOLDOWN: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Record {
+    /// `TrackerMetrics::score` when the tracker last connected.
+    score: f64,
+    /// Sessions in a row in which the tracker could not be connected to.
+    failures: u32,
+    /// Seconds since the Unix epoch.
+    updated: u64,
+}
+
+/// Tracker quality carried from one session to the next, keyed by normalized tracker URL so
+/// that torrents listing the same tracker share its record.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackerHistory {
+    records: HashMap<String, Record>,
+}
+impl TrackerHistory {
+    /// Reads the history at `path`; a missing file is an empty history.
+    pub fn load(path: &Path, now: SystemTime) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text, now),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+    /// Writes the history to a temporary file next to `path` and renames it into place, so
+    /// that a crash mid-write leaves the previous history intact.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = Path::new(&temp);
+        std::fs::write(temp, self.to_text())
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        std::fs::rename(temp, path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+    fn parse(text: &str, now: SystemTime) -> anyhow::Result<Self> {
+        let mut lines = text.lines();
+        match lines.next() {
+            Some(HEADER) => {}
+            header => anyhow::bail!("Unsupported tracker history header {:?}", header),
+        }
+        let mut records = HashMap::new();
+        for (number, line) in lines.enumerate() {
+            let malformed = || format!("Malformed tracker history line {}", number + 2);
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let [url, score, failures, updated] = fields[..] else {
+                anyhow::bail!(malformed());
+            };
+            let record = Record {
+                score: score.parse().with_context(malformed)?,
+                failures: failures.parse().with_context(malformed)?,
+                updated: updated.parse().with_context(malformed)?,
+            };
+            if age(record.updated, now) < MAX_RECORD_AGE {
+                records.insert(url.to_string(), record);
+            }
+        }
+        Ok(Self { records })
+    }
+    fn to_text(&self) -> String {
+        let mut urls = self.records.keys().collect::<Vec<_>>();
+        urls.sort();
+        let mut text = format!("{}\n", HEADER);
+        for url in urls {
+            let record = &self.records[url];
+            text.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                url, record.score, record.failures, record.updated
+            ));
+        }
+        text
+    }
+    /// The tracker's last recorded score, halved for every `SCORE_HALF_LIFE` since.
+    pub fn score(&self, url: &Url, now: SystemTime) -> Option<f64> {
+        let record = self.records.get(&normalize_tracker_url(url))?;
+        let half_lives = age(record.updated, now).as_secs_f64() / SCORE_HALF_LIFE.as_secs_f64();
+        Some(record.score * 0.5f64.powf(half_lives))
+    }
+    /// How much longer the tracker is held back for failing in recent sessions.
+    pub fn cooldown(&self, url: &Url, now: SystemTime) -> Option<Duration> {
+        let record = self.records.get(&normalize_tracker_url(url))?;
+        if record.failures == 0 {
+            return None;
+        }
+        let cooldown =
+            (FAILURE_COOLDOWN * 2u32.pow((record.failures - 1).min(10))).min(MAX_FAILURE_COOLDOWN);
+        cooldown
+            .checked_sub(age(record.updated, now))
+            .filter(|left| !left.is_zero())
+    }
+    pub fn record_success(&mut self, url: &Url, score: f64, now: SystemTime) {
+        self.records.insert(
+            normalize_tracker_url(url),
+            Record {
+                score,
+                failures: 0,
+                updated: unix_secs(now),
+            },
+        );
+    }
+    /// Counts a failed session, keeping the score from the last one that connected.
+    pub fn record_failure(&mut self, url: &Url, now: SystemTime) {
+        let record = self
+            .records
+            .entry(normalize_tracker_url(url))
+            .or_insert(Record {
+                score: 0.0,
+                failures: 0,
+                updated: 0,
+            });
+        record.failures += 1;
+        record.updated = unix_secs(now);
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn age(updated: u64, now: SystemTime) -> Duration {
+    Duration::from_secs(unix_secs(now).saturating_sub(updated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn tracker(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_aging() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let good = tracker("udp://Tracker.Example:6969/announce");
+        let dead = tracker("udp://dead.example:1337");
+        let mut history = TrackerHistory::default();
+        history.record_success(&good, 40.0, now);
+        history.record_failure(&dead, now);
+
+        let text = history.to_text();
+        assert_eq!(
+            text,
+            "magdl-tracker-history 1\n\
+             udp://dead.example:1337\t0\t1\t1700000000\n\
+             udp://tracker.example:6969\t40\t0\t1700000000\n"
+        );
+        assert_eq!(TrackerHistory::parse(&text, now).unwrap(), history);
+
+        // The same tracker listed by another torrent under a different path.
+        let listed = tracker("udp://tracker.example:6969/other");
+        assert_eq!(history.score(&listed, now), Some(40.0));
+        assert_eq!(history.score(&good, now + SCORE_HALF_LIFE), Some(20.0));
+        assert!(TrackerHistory::parse(&text, now + MAX_RECORD_AGE)
+            .unwrap()
+            .records
+            .is_empty());
+        assert!(TrackerHistory::parse("magdl-tracker-history 2\n", now).is_err());
+        assert!(TrackerHistory::parse(&format!("{}\nudp://x:1\t1\n", HEADER), now).is_err());
+        let error =
+            TrackerHistory::parse(&format!("{}\nudp://x:1\t1\tx\t0\n", HEADER), now).unwrap_err();
+        assert_eq!(error.to_string(), "Malformed tracker history line 2");
+    }
+
+    #[test]
+    fn test_save_replaces_file() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let dir = std::env::temp_dir().join(format!("magdl-history-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("trackers");
+        std::fs::write(&path, "old").unwrap();
+
+        let mut history = TrackerHistory::default();
+        history.record_success(&tracker("udp://tracker.example:6969"), 40.0, now);
+        history.save(&path).unwrap();
+        assert_eq!(TrackerHistory::load(&path, now).unwrap(), history);
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn test_cooldown_doubles_and_is_capped() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let dead = tracker("udp://dead.example:1337");
+        let mut history = TrackerHistory::default();
+        assert_eq!(history.cooldown(&dead, now), None);
+
+        history.record_failure(&dead, now);
+        assert_eq!(history.cooldown(&dead, now), Some(FAILURE_COOLDOWN));
+        assert_eq!(history.cooldown(&dead, now + HOUR / 4), Some(HOUR * 3 / 4));
+        assert_eq!(history.cooldown(&dead, now + HOUR), None);
+
+        history.record_failure(&dead, now);
+        assert_eq!(history.cooldown(&dead, now), Some(2 * FAILURE_COOLDOWN));
+        for _ in 0..20 {
+            history.record_failure(&dead, now);
+        }
+        assert_eq!(history.cooldown(&dead, now), Some(MAX_FAILURE_COOLDOWN));
+        assert_eq!(history.cooldown(&dead, now + MAX_FAILURE_COOLDOWN), None);
+
+        history.record_success(&dead, 1.0, now);
+        assert_eq!(history.cooldown(&dead, now), None);
+    }
+}
@@ -1,152 +1,786 @@
-use std::{net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs}, time::Duration, collections::HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::Context;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
-use futures::{stream::FuturesUnordered, StreamExt};
-use tokio::net::UdpSocket;
+use futures::{stream::FuturesUnordered, Stream, StreamExt};
+use tokio::{
+    net::UdpSocket,
+    sync::{watch, Semaphore},
+};
 use url::Url;
 
+use crate::{resolver::Resolver, tracker_history::TrackerHistory};
+
+/// Default size of the buffer announce responses are read into. Anything past it is cut off.
+pub const ANNOUNCE_BUFFER_BYTES: usize = 16 * 1024;
+/// How soon a tracker whose response came back truncated is asked again, with a smaller
+/// num_want, instead of waiting out its interval.
+const TRUNCATED_FOLLOW_UP: Duration = Duration::from_secs(30);
+/// Announces in flight at once, across all trackers.
+pub const MAX_CONCURRENT_ANNOUNCES: usize = 8;
+/// How long a tracker that failed in recent sessions waits before connecting, so that working
+/// trackers can supply enough peers without it.
+const COOLING_TRACKER_DELAY: Duration = Duration::from_secs(10);
+
 pub struct Trackers {
-    connections: Vec<TrackerConnection>,
+    trackers: Vec<Arc<Tracker>>,
+    resolver: Arc<Resolver>,
+    /// Every peer any tracker has returned, used to measure how many new peers an announce adds.
+    known_peers: Mutex<HashSet<SocketAddr>>,
+    receive_buffer: usize,
+    truncated_follow_up: Duration,
+    announce_slots: Semaphore,
+    /// What earlier sessions learned about the trackers, consulted as each one is added.
+    history: TrackerHistory,
+    cooling_delay: Duration,
 }
 impl Trackers {
-    pub async fn new(tracker_addrs: &Vec<Url>) -> Self {
-        let futures = tracker_addrs
+    /// Starts connecting to every distinct tracker in the background and returns at once.
+    pub fn new(tracker_addrs: &[Url]) -> Self {
+        Self::with_resolver(tracker_addrs, Arc::new(Resolver::system()))
+    }
+    pub fn with_resolver(tracker_addrs: &[Url], resolver: Arc<Resolver>) -> Self {
+        Self::with_history(tracker_addrs, resolver, TrackerHistory::default())
+    }
+    /// Trackers that scored well in `history` are ranked ahead until they connect, and those
+    /// that failed recently connect only after a delay.
+    pub fn with_history(
+        tracker_addrs: &[Url],
+        resolver: Arc<Resolver>,
+        history: TrackerHistory,
+    ) -> Self {
+        let mut trackers = Self {
+            trackers: Vec::new(),
+            resolver,
+            known_peers: Mutex::new(HashSet::new()),
+            receive_buffer: ANNOUNCE_BUFFER_BYTES,
+            truncated_follow_up: TRUNCATED_FOLLOW_UP,
+            announce_slots: Semaphore::new(MAX_CONCURRENT_ANNOUNCES),
+            history,
+            cooling_delay: COOLING_TRACKER_DELAY,
+        };
+        for tracker in tracker_addrs {
+            trackers.add(tracker.clone());
+        }
+        trackers
+    }
+
+    /// Size of the buffer announce responses are read into.
+    pub fn set_receive_buffer(&mut self, bytes: usize) {
+        self.receive_buffer = bytes;
+    }
+
+    /// Starts connecting to an extra tracker. Returns false if it duplicates a known one.
+    pub fn add(&mut self, tracker: Url) -> bool {
+        let normalized = normalize_tracker_url(&tracker);
+        if self
+            .trackers
             .iter()
-            .map(|tracker| TrackerConnection::new(tracker.clone()))
-            .collect::<FuturesUnordered<_>>();
-        let resolved = futures.collect::<Vec<_>>().await;
-        let conns = resolved
-            .into_iter()
-            .filter_map(|conn| match conn {
-                Ok(conn) => {
-                    println!("Connected to {}", conn.addr);
-                    Some(conn)
-                }
-                Err(e) => {
-                    println!("Tracker connection timed out");
-                    None
+            .any(|known| normalize_tracker_url(&known.url) == normalized)
+        {
+            return false;
+        }
+        let now = SystemTime::now();
+        let delay = match self.history.cooldown(&tracker, now) {
+            Some(_) => {
+                println!(
+                    "Tracker {} failed recently, connecting in {}s",
+                    tracker,
+                    self.cooling_delay.as_secs()
+                );
+                self.cooling_delay
+            }
+            None => Duration::ZERO,
+        };
+        let tracker = Arc::new(Tracker {
+            prior: self.history.score(&tracker, now),
+            url: tracker,
+            status: watch::channel(TrackerStatus::Resolving).0,
+            connection: OnceLock::new(),
+        });
+        tokio::spawn(Arc::clone(&tracker).connect(Arc::clone(&self.resolver), delay));
+        self.trackers.push(tracker);
+        true
+    }
+
+    /// Where each tracker's connection attempt stands, in list order.
+    #[cfg(test)]
+    pub fn states(&self) -> Vec<(Url, TrackerStatus)> {
+        self.trackers
+            .iter()
+            .map(|tracker| (tracker.url.clone(), tracker.status.borrow().clone()))
+            .collect()
+    }
+
+    /// Scrapes every tracker in parallel for swarm counts. Trackers that failed to connect
+    /// report why.
+    pub async fn scrape(&self, info_hash: Bytes) -> Vec<(Url, anyhow::Result<ScrapeStats>)> {
+        self.trackers
+            .iter()
+            .map(|tracker| {
+                let info_hash = info_hash.clone();
+                async move {
+                    let result = match tracker.connection().await {
+                        Ok(conn) => conn.scrape(info_hash).await,
+                        Err(failure) => Err(anyhow::anyhow!(failure)),
+                    };
+                    (tracker.url.clone(), result)
                 }
             })
-            .collect();
-        Self { connections: conns }
+            .collect::<FuturesUnordered<_>>()
+            .collect()
+            .await
     }
 
-    pub async fn announce(&self, peer_id: Bytes, info_hash: Bytes) -> Vec<SocketAddr> {
-        let futures = FuturesUnordered::new();
-        for conn in self.connections.iter() {
-            futures.push(conn.announce(AnnounceRequestDescriptor {
-                connection_id: conn.connection_id,
-                peer_id: peer_id.clone(),
-                info_hash: info_hash.clone(),
-                downloaded: 0,
-                left: 0,
-                uploaded: 0,
-                event: AnnounceEvent::None,
-            }))
-        }
-        let resolved = futures
-            .filter_map(|result| async {
-                match result {
-                    Ok(resp) => Some(resp),
-                    Err(_) => {
-                        println!("Failed to announce to tracker");
-                        None
+    /// Trackers ordered best first by their recorded metrics, or by their score in earlier
+    /// sessions until they connect; trackers with neither come last.
+    fn ranked(&self) -> Vec<&Arc<Tracker>> {
+        let mut ranked = self.trackers.iter().collect::<Vec<_>>();
+        ranked.sort_by_cached_key(|tracker| {
+            let score = match tracker.connection.get() {
+                Some(conn) => Some(conn.metrics().score()),
+                None => tracker.prior,
+            };
+            std::cmp::Reverse(score.map(score_key))
+        });
+        ranked
+    }
+
+    /// `history` updated with how each tracker did this session. Trackers still connecting
+    /// when the session ended are left as they were.
+    pub fn updated_history(&self, now: SystemTime) -> TrackerHistory {
+        let mut history = self.history.clone();
+        for tracker in &self.trackers {
+            match (&*tracker.status.borrow(), tracker.connection.get()) {
+                (TrackerStatus::Connected, Some(conn)) => {
+                    history.record_success(&tracker.url, conn.metrics().score(), now)
+                }
+                (TrackerStatus::Failed(_), _) => history.record_failure(&tracker.url, now),
+                _ => {}
+            }
+        }
+        history
+    }
+
+    /// Per-tracker metrics of the connected trackers, best ranked first.
+    pub fn status(&self) -> Vec<(Url, TrackerMetrics)> {
+        self.ranked()
+            .into_iter()
+            .filter_map(|tracker| tracker.connection.get())
+            .map(|conn| (conn.addr.clone(), conn.metrics()))
+            .collect()
+    }
+
+    /// Announces to every tracker as soon as it is connected and yields each tracker's peers
+    /// as they arrive, so that slow or dead trackers do not hold up the rest. At most
+    /// `MAX_CONCURRENT_ANNOUNCES` announces are in flight at once, best ranked trackers first.
+    /// Trackers are ranked when the stream is first polled, not when it is created, so that
+    /// metrics recorded in between count. A tracker whose response was truncated is asked again
+    /// shortly after with a smaller num_want.
+    pub fn announce_stream(
+        &self,
+        peer_id: Bytes,
+        info_hash: Bytes,
+    ) -> impl Stream<Item = TrackerPeers> + '_ {
+        let ranked = futures::stream::once(futures::future::lazy(move |_| self.ranked()));
+        ranked.flat_map(move |ranked| self.announce_ranked(ranked, &peer_id, &info_hash))
+    }
+
+    /// Announces to `ranked` trackers for `announce_stream`.
+    fn announce_ranked<'a>(
+        &'a self,
+        ranked: Vec<&'a Arc<Tracker>>,
+        peer_id: &Bytes,
+        info_hash: &Bytes,
+    ) -> impl Stream<Item = TrackerPeers> + 'a {
+        let announces = ranked.into_iter().map(|tracker| {
+            let peer_id = peer_id.clone();
+            let info_hash = info_hash.clone();
+            let announces = futures::stream::unfold(Some(NUM_WANT_DEFAULT), move |num_want| {
+                let peer_id = peer_id.clone();
+                let info_hash = info_hash.clone();
+                async move {
+                    let num_want = num_want?;
+                    let conn = tracker.connection().await.ok()?;
+                    if num_want != NUM_WANT_DEFAULT {
+                        tokio::time::sleep(self.truncated_follow_up).await;
+                    }
+                    let descriptor = AnnounceRequestDescriptor {
+                        connection_id: conn.connection_id,
+                        peer_id,
+                        info_hash,
+                        downloaded: 0,
+                        left: 0,
+                        uploaded: 0,
+                        event: AnnounceEvent::None,
+                        num_want,
+                    };
+                    let permit = self
+                        .announce_slots
+                        .acquire()
+                        .await
+                        .expect("announce semaphore is never closed");
+                    let announced = conn
+                        .announce(descriptor, self.receive_buffer, &self.known_peers)
+                        .await;
+                    drop(permit);
+                    match announced {
+                        Ok(announced) => {
+                            let peers = TrackerPeers {
+                                peers: announced.peers,
+                                quality: conn.metrics().source_quality(),
+                            };
+                            Some((peers, announced.follow_up))
+                        }
+                        Err(e) => {
+                            println!("Failed to announce to {}: {:#}", tracker.url, e);
+                            None
+                        }
                     }
                 }
-            })
+            });
+            Box::pin(announces)
+        });
+        futures::stream::select_all(announces)
+    }
+
+    /// Like `announce_stream`, but ends once `enough` distinct peers have arrived. Announces
+    /// still in flight are dropped then, which closes their sockets; nothing about them is
+    /// recorded, so they are simply sent again next time. All of these announces carry no
+    /// event, so none of them has to complete.
+    pub fn announce_until(
+        &self,
+        peer_id: Bytes,
+        info_hash: Bytes,
+        enough: usize,
+    ) -> impl Stream<Item = TrackerPeers> + '_ {
+        let announces = Box::pin(self.announce_stream(peer_id, info_hash));
+        futures::stream::unfold(
+            (announces, HashSet::new()),
+            move |(mut announces, mut seen)| async move {
+                if seen.len() >= enough {
+                    println!(
+                        "{} peers from trackers, not waiting for the rest",
+                        seen.len()
+                    );
+                    return None;
+                }
+                let peers = announces.next().await?;
+                seen.extend(peers.peers.iter().copied());
+                Some((peers, (announces, seen)))
+            },
+        )
+    }
+
+    #[cfg(test)]
+    /// Announces to every tracker and returns the distinct peers once all have answered or
+    /// failed.
+    pub async fn announce(&self, peer_id: Bytes, info_hash: Bytes) -> Vec<SocketAddr> {
+        let resolved = self
+            .announce_stream(peer_id, info_hash)
             .collect::<Vec<_>>()
             .await;
         let mut uniques = HashSet::new();
-        let mut flattened = resolved.into_iter().flatten().collect::<Vec<_>>();
+        let mut flattened = resolved
+            .into_iter()
+            .flat_map(|announced| announced.peers)
+            .collect::<Vec<_>>();
         flattened.retain(|i| uniques.insert(*i));
         flattened
     }
 }
 
+/// Why a tracker could not be connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerFailure {
+    /// The host name did not resolve.
+    Dns(String),
+    /// The host or port rejected us, e.g. with an ICMP unreachable.
+    Unreachable(String),
+    /// No connect response in time.
+    Timeout,
+    Other(String),
+}
+impl TrackerFailure {
+    fn classify(error: &anyhow::Error) -> Self {
+        if error.is::<tokio::time::error::Elapsed>() {
+            return TrackerFailure::Timeout;
+        }
+        match error.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+            Some(
+                std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::HostUnreachable
+                | std::io::ErrorKind::NetworkUnreachable,
+            ) => TrackerFailure::Unreachable(error.to_string()),
+            _ => TrackerFailure::Other(format!("{:#}", error)),
+        }
+    }
+}
+impl std::fmt::Display for TrackerFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackerFailure::Dns(e) => write!(f, "DNS lookup failed: {}", e),
+            TrackerFailure::Unreachable(e) => write!(f, "unreachable: {}", e),
+            TrackerFailure::Timeout => write!(f, "timed out"),
+            TrackerFailure::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerStatus {
+    Resolving,
+    Connecting,
+    Connected,
+    Failed(TrackerFailure),
+}
+impl TrackerStatus {
+    fn is_settled(&self) -> bool {
+        matches!(self, TrackerStatus::Connected | TrackerStatus::Failed(_))
+    }
+}
+impl std::fmt::Display for TrackerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackerStatus::Resolving => write!(f, "resolving"),
+            TrackerStatus::Connecting => write!(f, "connecting"),
+            TrackerStatus::Connected => write!(f, "connected"),
+            TrackerStatus::Failed(failure) => write!(f, "failed, {}", failure),
+        }
+    }
+}
+
+/// One listed tracker. Its connection is made in the background by `connect`.
+struct Tracker {
+    url: Url,
+    /// Score from earlier sessions, aged.
+    prior: Option<f64>,
+    status: watch::Sender<TrackerStatus>,
+    connection: OnceLock<TrackerConnection>,
+}
+impl Tracker {
+    async fn connect(self: Arc<Self>, resolver: Arc<Resolver>, delay: Duration) {
+        tokio::time::sleep(delay).await;
+        let started = Instant::now();
+        if let Err(e) = tracker_port(&self.url) {
+            return self.set_status(TrackerStatus::Failed(TrackerFailure::Other(e.to_string())));
+        }
+        let s_addr = match tracker_socket_addr(&self.url, &resolver).await {
+            Ok(s_addr) => s_addr,
+            Err(e) => {
+                let failure = TrackerFailure::Dns(format!("{:#}", e));
+                return self.set_status(TrackerStatus::Failed(failure));
+            }
+        };
+        self.set_status(TrackerStatus::Connecting);
+        match TrackerConnection::connect(s_addr).await {
+            Ok(connection_id) => {
+                let conn =
+                    TrackerConnection::new(self.url.clone(), resolver, connection_id, started);
+                let _ = self.connection.set(conn);
+                self.set_status(TrackerStatus::Connected);
+            }
+            Err(e) => {
+                let failure = TrackerFailure::classify(&e);
+                self.set_status(TrackerStatus::Failed(failure));
+            }
+        }
+    }
+    fn set_status(&self, status: TrackerStatus) {
+        if status.is_settled() {
+            println!("Tracker {} {}", self.url, status);
+        }
+        self.status.send_replace(status);
+    }
+    /// Waits for the connection attempt to finish.
+    async fn connection(&self) -> Result<&TrackerConnection, TrackerFailure> {
+        let mut status = self.status.subscribe();
+        let settled = match status.wait_for(TrackerStatus::is_settled).await {
+            Ok(settled) => settled.clone(),
+            Err(_) => TrackerStatus::Failed(TrackerFailure::Other("Connect task ended".into())),
+        };
+        match settled {
+            TrackerStatus::Failed(failure) => Err(failure),
+            _ => self
+                .connection
+                .get()
+                .ok_or_else(|| TrackerFailure::Other("Connection missing".into())),
+        }
+    }
+}
+
+/// Canonical form of a tracker URL, used to spot the same tracker listed more than once.
+/// Scheme and host are case-folded and the default port is made explicit. UDP trackers are
+/// identified by host and port alone, since the UDP protocol never sends the path; for HTTP(S)
+/// the fragment and a trailing slash are dropped but the path and query are kept.
+pub fn normalize_tracker_url(url: &Url) -> String {
+    let scheme = url.scheme().to_ascii_lowercase();
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    let authority = match url.port_or_known_default() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    };
+    if scheme == "udp" {
+        return format!("{}://{}", scheme, authority);
+    }
+    let path = url.path().trim_end_matches('/');
+    match url.query() {
+        Some(query) => format!("{}://{}{}?{}", scheme, authority, path, query),
+        None => format!("{}://{}{}", scheme, authority, path),
+    }
+}
+
+/// Address of the tracker at `url`, which may name it by an IPv4 or IPv6 literal or a
+/// hostname. A hostname with both kinds of address is reached over IPv4, where most swarms
+/// live.
+pub async fn tracker_socket_addr(url: &Url, resolver: &Resolver) -> anyhow::Result<SocketAddr> {
+    let port = tracker_port(url)?;
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => Ok(SocketAddr::new(ip.into(), port)),
+        Some(url::Host::Ipv6(ip)) => Ok(SocketAddr::new(ip.into(), port)),
+        // udp:// is not a special scheme to the url crate, so its IPv4 hosts stay domains.
+        Some(url::Host::Domain(host)) if host.parse::<IpAddr>().is_ok() => {
+            Ok(SocketAddr::new(host.parse()?, port))
+        }
+        Some(url::Host::Domain(host)) => {
+            let addrs = resolver.resolve(host, port).await?;
+            addrs
+                .iter()
+                .find(|addr| addr.is_ipv4())
+                .or(addrs.first())
+                .copied()
+                .context("Tracker host resolved to no addresses")
+        }
+        None => anyhow::bail!("Tracker URL {} has no host", url),
+    }
+}
+
+/// Wildcard address of the same family as `remote`, for a socket that talks to it.
+fn local_bind_addr(remote: SocketAddr) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+/// Port to contact a tracker on. UDP trackers have no conventional port, so one must be given.
+pub fn tracker_port(url: &Url) -> anyhow::Result<u16> {
+    match (url.scheme(), url.port()) {
+        ("udp" | "http" | "https", Some(port)) => Ok(port),
+        ("udp", None) => anyhow::bail!("UDP tracker URL {} has no port", url),
+        ("http", None) => Ok(80),
+        ("https", None) => Ok(443),
+        (scheme, _) => anyhow::bail!("Unsupported tracker scheme {} in {}", scheme, url),
+    }
+}
+
+/// Weight of the newest sample in the decayed tracker metrics.
+const METRIC_DECAY: f64 = 0.3;
+/// Score of a tracker returning about 50 new peers in a 0.5s round trip.
+const TYPICAL_SCORE: f64 = 100.0;
+
+fn decay(average: f64, sample: f64) -> f64 {
+    average + METRIC_DECAY * (sample - average)
+}
+
+/// Exponentially decayed quality measurements for one tracker.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerMetrics {
+    pub connect_rtt: Duration,
+    pub announce_rtt: Option<Duration>,
+    pub response_bytes: f64,
+    /// Previously unknown peers contributed per announce.
+    pub peer_yield: f64,
+    pub announces: u32,
+    pub failures: u32,
+    /// Responses that ended in a partial peer entry.
+    pub truncated: u32,
+}
+impl TrackerMetrics {
+    fn record_announce(&mut self, rtt: Duration, response_bytes: usize, new_peers: usize) {
+        if self.announces == 0 {
+            self.response_bytes = response_bytes as f64;
+            self.peer_yield = new_peers as f64;
+        } else {
+            self.response_bytes = decay(self.response_bytes, response_bytes as f64);
+            self.peer_yield = decay(self.peer_yield, new_peers as f64);
+        }
+        self.announce_rtt = Some(match self.announce_rtt {
+            Some(average) => {
+                Duration::from_secs_f64(decay(average.as_secs_f64(), rtt.as_secs_f64()))
+            }
+            None => rtt,
+        });
+        self.announces += 1;
+    }
+    fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+    /// Higher is better: new peers per second of round trip, scaled by the success rate.
+    pub fn score(&self) -> f64 {
+        let rtt = self.announce_rtt.unwrap_or(self.connect_rtt).as_secs_f64();
+        let attempts = (self.announces + self.failures + 1) as f64;
+        let success = (self.announces + 1) as f64 / attempts;
+        success * (self.peer_yield + 1.0) / (rtt + 0.05)
+    }
+    /// `score` mapped into `0.0..1.0` for `dial_queue::dial_score`; a tracker scoring
+    /// `TYPICAL_SCORE` gives 0.5.
+    pub fn source_quality(&self) -> f64 {
+        let score = self.score();
+        score / (score + TYPICAL_SCORE)
+    }
+}
+
+/// Tracker score as a sortable integer.
+fn score_key(score: f64) -> u64 {
+    (score * 1000.0) as u64
+}
+impl std::fmt::Display for TrackerMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let announce_rtt = self.announce_rtt.unwrap_or_default();
+        write!(
+            f,
+            "connect {}ms, announce {}ms, {:.0} bytes, yield {:.1}, {} ok / {} failed / {} truncated",
+            self.connect_rtt.as_millis(),
+            announce_rtt.as_millis(),
+            self.response_bytes,
+            self.peer_yield,
+            self.announces,
+            self.failures,
+            self.truncated
+        )
+    }
+}
+
+#[derive(Debug)]
+struct CachedAnnounce {
+    received: Instant,
+    interval: Duration,
+    peers: Vec<SocketAddr>,
+}
+
+/// Peers from one announce as yielded by `Trackers::announce_stream`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerPeers {
+    pub peers: Vec<SocketAddr>,
+    /// The announcing tracker's `TrackerMetrics::source_quality` after the announce.
+    pub quality: f64,
+}
+
+/// Peers from one announce, and the smaller num_want to ask again with if the response looked
+/// truncated.
+#[derive(Debug)]
+struct Announced {
+    peers: Vec<SocketAddr>,
+    follow_up: Option<i32>,
+}
+
 #[derive(Debug)]
 struct TrackerConnection {
     pub addr: Url,
+    resolver: Arc<Resolver>,
     pub connection_id: i64,
+    /// Most recent successful plain announce per info hash, reused until its interval lapses.
+    announce_cache: Mutex<HashMap<Bytes, CachedAnnounce>>,
+    metrics: Mutex<TrackerMetrics>,
 }
 
 impl TrackerConnection {
-    async fn new(addr: Url) -> anyhow::Result<Self> {
-        let connection_id = TrackerConnection::connect(addr.clone()).await?;
-        Ok(Self {
+    fn new(addr: Url, resolver: Arc<Resolver>, connection_id: i64, started: Instant) -> Self {
+        let metrics = TrackerMetrics {
+            connect_rtt: started.elapsed(),
+            ..TrackerMetrics::default()
+        };
+        Self {
             addr,
+            resolver,
             connection_id,
-        })
+            announce_cache: Mutex::new(HashMap::new()),
+            metrics: Mutex::new(metrics),
+        }
     }
-    async fn connect(addr: Url) -> anyhow::Result<i64> {
-        let host_port = format!("{}:{}", addr.host_str().unwrap(), addr.port().unwrap_or(80));
-        let s_addr = host_port.to_socket_addrs()?.last().unwrap();
-        let socket = UdpSocket::bind("0.0.0.0:0")
+    fn metrics(&self) -> TrackerMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+    async fn connect(s_addr: SocketAddr) -> anyhow::Result<i64> {
+        let socket = UdpSocket::bind(local_bind_addr(s_addr))
             .await
             .context("Failed to establish UDP Socket")?;
-        let connection_id = TrackerConnection::handshake(&socket, s_addr).await?;
+        // Connected, so that an ICMP unreachable is reported instead of waiting out the timeout.
+        socket.connect(s_addr).await?;
+        let connection_id = TrackerConnection::handshake(&socket).await?;
         Ok(connection_id)
     }
-    async fn handshake(socket: &UdpSocket, addr: SocketAddr) -> anyhow::Result<i64> {
+    async fn handshake(socket: &UdpSocket) -> anyhow::Result<i64> {
         let request = ConnectRequest::new();
-        let bytes_sent = socket.send_to(&request.to_bytes(), &addr).await?;
-        if bytes_sent != CONNECT_REQUEST_SIZE {
-            anyhow::bail!("Unable to send connect request");
-        }
         let mut bytes_recv = [0u8; CONNECT_RESPONSE_SIZE];
         let conn_result = tokio::time::timeout(Duration::from_secs(3), async {
             loop {
-                let (n, tracker) = socket.recv_from(&mut bytes_recv).await?;
-                if tracker != addr {
+                // Resent until answered. Besides covering a lost datagram, the next send is
+                // what reports an ICMP unreachable; a pending recv is never woken for it.
+                let bytes_sent = socket.send(&request.to_bytes()).await?;
+                if bytes_sent != CONNECT_REQUEST_SIZE {
+                    anyhow::bail!("Unable to send connect request");
+                }
+                let received = socket.recv(&mut bytes_recv);
+                let Ok(n) = tokio::time::timeout(CONNECT_RESEND, received).await else {
                     continue;
-                } else if n != CONNECT_RESPONSE_SIZE {
+                };
+                if n? != CONNECT_RESPONSE_SIZE {
                     anyhow::bail!("Unable to read connect response");
                 }
-                break;
+                return Ok(());
             }
-            Ok(())
-        }).await?;
+        })
+        .await?;
 
-        if conn_result.is_err() {
-            return Err(conn_result.unwrap_err().into());
-        }
+        conn_result?;
         let response = ConnectResponse::from_bytes(&bytes_recv);
         if response.transaction_id != request.transaction_id {
             anyhow::bail!("Mismatched transaction ids");
         }
         Ok(response.connection_id)
     }
-    async fn announce(&self, descriptor: AnnounceRequestDescriptor) -> anyhow::Result<Vec<SocketAddr>> {
-        let host_port = format!("{}:{}", self.addr.host_str().unwrap(), self.addr.port().unwrap_or(80));
-        let s_addr = host_port.to_socket_addrs()?.last().unwrap();
-        let request = AnnounceRequest::new(descriptor);
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .await
-            .context("Failed to establish UDP Socket")?;
-        let bytes_sent = socket.send_to(&request.to_bytes(), &s_addr).await?;
-        if bytes_sent != ANNOUNCE_REQUEST_BYTES {
-            anyhow::bail!("Unable to send connect request");
-        }
-        let mut bytes_recv = [0u8; 4000];
-        let conn_result: anyhow::Result<usize> = tokio::time::timeout(Duration::from_secs(3), async {
-            Ok(loop {
-                let (n, tracker) = socket.recv_from(&mut bytes_recv).await?;
-                if tracker != s_addr {
-                    continue;
+    /// Announces and records the round trip, response size and how many of the returned peers
+    /// were not yet in `known_peers`. The response is read into `receive_buffer` bytes.
+    async fn announce(
+        &self,
+        descriptor: AnnounceRequestDescriptor,
+        receive_buffer: usize,
+        known_peers: &Mutex<HashSet<SocketAddr>>,
+    ) -> anyhow::Result<Announced> {
+        // Event announces change the tracker's view of us and must always be sent.
+        let cacheable = matches!(descriptor.event, AnnounceEvent::None);
+        if cacheable {
+            let cache = self.announce_cache.lock().unwrap();
+            if let Some(cached) = cache.get(&descriptor.info_hash) {
+                if cached.received.elapsed() < cached.interval {
+                    return Ok(Announced {
+                        peers: cached.peers.clone(),
+                        follow_up: None,
+                    });
                 }
-                break n;
-            })
-        }).await?;
-        if conn_result.is_err() {
-            return Err(conn_result.unwrap_err().into());
+            }
         }
-        let response = AnnounceResponse::from_bytes(&bytes_recv, conn_result.unwrap());
+        let info_hash = descriptor.info_hash.clone();
+        let request = AnnounceRequest::new(descriptor);
+        let mut bytes_recv = vec![0u8; receive_buffer];
+        let started = Instant::now();
+        let (n, tracker) = match self.exchange(&request.to_bytes(), &mut bytes_recv).await {
+            Ok(received) => received,
+            Err(e) => {
+                self.metrics.lock().unwrap().record_failure();
+                return Err(e);
+            }
+        };
+        let rtt = started.elapsed();
+        let response = match AnnounceResponse::from_bytes(&bytes_recv[..n], tracker.is_ipv6()) {
+            Ok(response) => response,
+            Err(e) => {
+                self.metrics.lock().unwrap().record_failure();
+                return Err(e);
+            }
+        };
         if response.transaction_id != request.transaction_id {
+            self.metrics.lock().unwrap().record_failure();
             anyhow::bail!("Mismatched transaction ids");
         }
-        Ok(response.peers)
-
+        // A partial trailing entry, or a full buffer with fewer peers than the swarm has, means
+        // the datagram was cut short on the way.
+        let swarm = response.leechers as usize + response.seeders as usize;
+        let truncated = response.fragment > 0
+            || (n == bytes_recv.len() && response.peers.len() < swarm);
+        if response.fragment > 0 {
+            println!(
+                "Ignoring {} trailing bytes of a truncated announce response from {}",
+                response.fragment, self.addr
+            );
+            self.metrics.lock().unwrap().truncated += 1;
+        }
+        let follow_up = truncated
+            .then(|| (response.peers.len() / 2).max(1) as i32)
+            .filter(|smaller| request.num_want < 0 || *smaller < request.num_want);
+        let new_peers = {
+            let mut known_peers = known_peers.lock().unwrap();
+            response
+                .peers
+                .iter()
+                .filter(|peer| known_peers.insert(**peer))
+                .count()
+        };
+        self.metrics
+            .lock()
+            .unwrap()
+            .record_announce(rtt, n, new_peers);
+        // A truncated response is not cached, so that its follow-up is actually sent.
+        if cacheable && !truncated {
+            self.announce_cache.lock().unwrap().insert(
+                info_hash,
+                CachedAnnounce {
+                    received: Instant::now(),
+                    interval: Duration::from_secs(response.interval as u64),
+                    peers: response.peers.clone(),
+                },
+            );
+        }
+        Ok(Announced {
+            peers: response.peers,
+            follow_up,
+        })
+    }
+    async fn scrape(&self, info_hash: Bytes) -> anyhow::Result<ScrapeStats> {
+        let request = ScrapeRequest::new(self.connection_id, info_hash);
+        let mut bytes_recv = [0u8; SCRAPE_RESPONSE_BYTES];
+        let (n, _) = self.exchange(&request.to_bytes(), &mut bytes_recv).await?;
+        if n < 8 {
+            anyhow::bail!("Scrape response too short");
+        }
+        let action = BigEndian::read_u32(&bytes_recv[0..4]);
+        let transaction_id = BigEndian::read_u32(&bytes_recv[4..8]);
+        if transaction_id != request.transaction_id {
+            anyhow::bail!("Mismatched transaction ids");
+        }
+        if action == ACTION_ERROR {
+            anyhow::bail!(
+                "Tracker error: {}",
+                String::from_utf8_lossy(&bytes_recv[8..n])
+            );
+        }
+        if n != SCRAPE_RESPONSE_BYTES {
+            anyhow::bail!("Unable to read scrape response");
+        }
+        Ok(ScrapeStats::from_bytes(&bytes_recv[8..n]))
+    }
+    /// Sends one request from a fresh socket and waits for the tracker's reply. Returns its
+    /// length and the address it came from.
+    async fn exchange(
+        &self,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> anyhow::Result<(usize, SocketAddr)> {
+        let s_addr = tracker_socket_addr(&self.addr, &self.resolver).await?;
+        let socket = UdpSocket::bind(local_bind_addr(s_addr))
+            .await
+            .context("Failed to establish UDP Socket")?;
+        let bytes_sent = socket.send_to(request, &s_addr).await?;
+        if bytes_sent != request.len() {
+            anyhow::bail!("Unable to send tracker request");
+        }
+        tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                let (n, tracker) = socket.recv_from(response).await?;
+                if tracker == s_addr {
+                    return Ok((n, tracker));
+                }
+            }
+        })
+        .await?
     }
 }
 
@@ -160,6 +794,7 @@ struct ConnectRequest {
 const PROTOCOL_ID: i64 = 0x41727101980;
 
 const CONNECT_REQUEST_SIZE: usize = 16;
+const CONNECT_RESEND: Duration = Duration::from_millis(500);
 const CONNECT_RESPONSE_SIZE: usize = 16;
 impl ConnectRequest {
     fn new() -> Self {
@@ -178,6 +813,7 @@ impl ConnectRequest {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 struct ConnectResponse {
     action: u32,
@@ -197,6 +833,7 @@ impl ConnectResponse {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Copy, Clone)]
 pub enum AnnounceEvent {
     None = 0,
@@ -231,9 +868,12 @@ pub struct AnnounceRequestDescriptor {
     pub left: u64,
     pub uploaded: u64,
     pub event: AnnounceEvent,
+    /// How many peers to ask for; `NUM_WANT_DEFAULT` leaves it to the tracker.
+    pub num_want: i32,
 }
 
 const ANNOUNCE_REQUEST_BYTES: usize = 98;
+pub const NUM_WANT_DEFAULT: i32 = -1;
 impl AnnounceRequest {
     fn new(descriptor: AnnounceRequestDescriptor) -> Self {
         Self {
@@ -248,7 +888,7 @@ impl AnnounceRequest {
             event: descriptor.event,
             ip_address: 0,
             key: rand::random(),
-            num_want: -1,
+            num_want: descriptor.num_want,
             port: 6881,
         }
     }
@@ -271,6 +911,7 @@ impl AnnounceRequest {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 struct AnnounceResponse {
     action: u32,
@@ -279,33 +920,754 @@ struct AnnounceResponse {
     leechers: u32,
     seeders: u32,
     peers: Vec<SocketAddr>,
+    /// Length of a partial peer entry at the end of the datagram, which was ignored.
+    fragment: usize,
 }
+/// Bytes per peer entry in an announce response received over IPv4.
+const PEER_ENTRY_BYTES: usize = 6;
+/// Bytes per peer entry in an announce response received over IPv6 (BEP 15).
+const PEER_ENTRY_BYTES_V6: usize = 18;
 impl AnnounceResponse {
-    fn from_bytes(bytes: &[u8], length: usize) -> Self {
+    /// `ipv6` is whether the response came over IPv6, in which case its peers are IPv6 too.
+    fn from_bytes(bytes: &[u8], ipv6: bool) -> anyhow::Result<Self> {
+        if bytes.len() < 8 {
+            anyhow::bail!("Announce response of {} bytes", bytes.len());
+        }
         let action = BigEndian::read_u32(&bytes[0..4]);
         let transaction_id = BigEndian::read_u32(&bytes[4..8]);
+        if action == ACTION_ERROR {
+            anyhow::bail!("Tracker error: {}", String::from_utf8_lossy(&bytes[8..]));
+        }
+        if bytes.len() < 20 {
+            anyhow::bail!("Announce response of {} bytes", bytes.len());
+        }
         let interval = BigEndian::read_u32(&bytes[8..12]);
         let leechers = BigEndian::read_u32(&bytes[12..16]);
         let seeders = BigEndian::read_u32(&bytes[16..20]);
-        let peer_list = &bytes[20..length];
-        if peer_list.len() % 6 != 0 {
-            panic!("Invalid peer list size");
-        }
+        let peer_list = &bytes[20..];
+        let entry_bytes = if ipv6 {
+            PEER_ENTRY_BYTES_V6
+        } else {
+            PEER_ENTRY_BYTES
+        };
+        let entries = peer_list.chunks_exact(entry_bytes);
+        let fragment = entries.remainder().len();
         let mut peers = Vec::new();
-        for address in peer_list.chunks(6) {
-            let ip = Ipv4Addr::new(address[0], address[1], address[2], address[3]);
-            let port = BigEndian::read_u16(&address[4..6]);
-            let peer = SocketAddr::new(IpAddr::V4(ip), port);
-            peers.push(peer);
+        for address in entries {
+            let (ip, port) = address.split_at(entry_bytes - 2);
+            let ip = match <[u8; 16]>::try_from(ip) {
+                Ok(octets) => IpAddr::V6(Ipv6Addr::from(octets)),
+                Err(_) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+            };
+            peers.push(SocketAddr::new(ip, BigEndian::read_u16(port)));
         }
-        Self {
+        Ok(Self {
             action,
             transaction_id,
             interval,
             leechers,
             seeders,
             peers,
+            fragment,
+        })
+    }
+}
+
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const SCRAPE_REQUEST_BYTES: usize = 36;
+const SCRAPE_RESPONSE_BYTES: usize = 20;
+
+#[derive(Debug)]
+struct ScrapeRequest {
+    connection_id: i64,
+    transaction_id: u32,
+    info_hash: Bytes,
+}
+impl ScrapeRequest {
+    fn new(connection_id: i64, info_hash: Bytes) -> Self {
+        Self {
+            connection_id,
+            transaction_id: rand::random(),
+            info_hash,
         }
     }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; SCRAPE_REQUEST_BYTES];
+        BigEndian::write_i64(&mut bytes[0..8], self.connection_id);
+        BigEndian::write_u32(&mut bytes[8..12], ACTION_SCRAPE);
+        BigEndian::write_u32(&mut bytes[12..16], self.transaction_id);
+        bytes[16..36].copy_from_slice(&self.info_hash);
+        bytes
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+impl ScrapeStats {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            seeders: BigEndian::read_u32(&bytes[0..4]),
+            completed: BigEndian::read_u32(&bytes[4..8]),
+            leechers: BigEndian::read_u32(&bytes[8..12]),
+        }
+    }
+}
+
+/// True when no tracker reported a seeder, counting failed scrapes as reporting none.
+pub fn swarm_has_no_seeders(results: &[(Url, anyhow::Result<ScrapeStats>)]) -> bool {
+    results
+        .iter()
+        .all(|(_, result)| !matches!(result, Ok(stats) if stats.seeders > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// How a `MockTracker` answers: every announce returns `peers` with an interval of
+    /// `interval` seconds after `delay`, and every scrape returns `stats`.
+    struct MockOptions {
+        bind: &'static str,
+        interval: u32,
+        peers: Vec<SocketAddr>,
+        stats: ScrapeStats,
+        delay: Duration,
+    }
+    impl Default for MockOptions {
+        fn default() -> Self {
+            Self {
+                bind: "127.0.0.1:0",
+                interval: 1800,
+                peers: Vec::new(),
+                stats: ScrapeStats {
+                    seeders: 0,
+                    completed: 0,
+                    leechers: 0,
+                },
+                delay: Duration::ZERO,
+            }
+        }
+    }
+
+    /// Minimal UDP tracker. Answers connects, announces and scrapes as set out in its options.
+    struct MockTracker {
+        url: Url,
+        announces: Arc<AtomicUsize>,
+    }
+    impl MockTracker {
+        async fn spawn(options: MockOptions) -> Self {
+            let MockOptions {
+                bind,
+                interval,
+                peers,
+                stats,
+                delay,
+            } = options;
+            let socket = UdpSocket::bind(bind).await.unwrap();
+            let url = Url::parse(&format!("udp://{}/announce", socket.local_addr().unwrap()))
+                .unwrap();
+            let announces = Arc::new(AtomicUsize::new(0));
+            let counter = Arc::clone(&announces);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1500];
+                loop {
+                    let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                    let action = BigEndian::read_u32(&buf[8..12]);
+                    let transaction_id = BigEndian::read_u32(&buf[12..16]);
+                    let mut response = vec![0u8; 8];
+                    BigEndian::write_u32(&mut response[0..4], action);
+                    BigEndian::write_u32(&mut response[4..8], transaction_id);
+                    match action {
+                        0 if n == CONNECT_REQUEST_SIZE => {
+                            response.extend_from_slice(&0x1234i64.to_be_bytes());
+                        }
+                        1 if n == ANNOUNCE_REQUEST_BYTES => {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(delay).await;
+                            response.extend_from_slice(&interval.to_be_bytes());
+                            response.extend_from_slice(&0u32.to_be_bytes());
+                            response.extend_from_slice(&(peers.len() as u32).to_be_bytes());
+                            for peer in peers.iter() {
+                                match peer.ip() {
+                                    IpAddr::V4(ip) => response.extend_from_slice(&ip.octets()),
+                                    IpAddr::V6(ip) => response.extend_from_slice(&ip.octets()),
+                                }
+                                response.extend_from_slice(&peer.port().to_be_bytes());
+                            }
+                        }
+                        ACTION_SCRAPE if n == SCRAPE_REQUEST_BYTES => {
+                            response.extend_from_slice(&stats.seeders.to_be_bytes());
+                            response.extend_from_slice(&stats.completed.to_be_bytes());
+                            response.extend_from_slice(&stats.leechers.to_be_bytes());
+                        }
+                        _ => continue,
+                    }
+                    socket.send_to(&response, from).await.unwrap();
+                }
+            });
+            Self { url, announces }
+        }
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port)
+    }
+
+    #[test]
+    fn test_normalize_tracker_url() {
+        let cases = [
+            ("udp://tracker.opentrackr.org:1337/announce", "udp://tracker.opentrackr.org:1337"),
+            ("udp://Tracker.OpenTrackr.org:1337/announce/", "udp://tracker.opentrackr.org:1337"),
+            ("UDP://tracker.opentrackr.org:1337", "udp://tracker.opentrackr.org:1337"),
+            ("http://tracker.example.com/announce", "http://tracker.example.com:80/announce"),
+            ("http://Tracker.Example.com:80/announce/", "http://tracker.example.com:80/announce"),
+            ("https://tracker.example.com/announce", "https://tracker.example.com:443/announce"),
+            ("http://t.example/ann.php?uk=xyz#frag", "http://t.example:80/ann.php?uk=xyz"),
+            ("http://t.example:8080/announce", "http://t.example:8080/announce"),
+        ];
+        for (input, expected) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(normalize_tracker_url(&url), expected, "{}", input);
+        }
+        let udp = Url::parse("udp://tracker.example.com:80/announce").unwrap();
+        let http = Url::parse("http://tracker.example.com:80/announce").unwrap();
+        assert_ne!(normalize_tracker_url(&udp), normalize_tracker_url(&http));
+    }
+
+    #[test]
+    fn test_tracker_port() {
+        let cases = [
+            ("udp://tracker.example:2950/announce?passkey=abc", Some(2950)),
+            ("udp://tracker.example:1337", Some(1337)),
+            ("udp://tracker.example/announce", None),
+            ("http://t.example/ann.php?uk=xyz", Some(80)),
+            ("http://t.example:8080/x/announce.php?pk=1", Some(8080)),
+            ("https://t.example/announce#frag", Some(443)),
+            ("https://t.example:8443/a/announce", Some(8443)),
+            ("wss://t.example/announce", None),
+        ];
+        for (input, port) in cases {
+            let url = Url::parse(input).unwrap();
+            assert_eq!(tracker_port(&url).ok(), port, "{}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_trackers_share_one_connection() {
+        let tracker = MockTracker::spawn(MockOptions {
+            peers: vec![peer(1)],
+            ..MockOptions::default()
+        })
+        .await;
+        let mut duplicate = tracker.url.clone();
+        duplicate.set_path("/announce/");
+        let trackers = Trackers::new(&[tracker.url.clone(), duplicate]);
+        assert_eq!(trackers.trackers.len(), 1);
+
+        let peers = trackers.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await;
+        assert_eq!(peers, vec![peer(1)]);
+        assert_eq!(tracker.announces.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_cached_within_interval() {
+        let tracker = MockTracker::spawn(MockOptions {
+            peers: vec![peer(1), peer(2)],
+            ..MockOptions::default()
+        })
+        .await;
+        let trackers = Trackers::new(std::slice::from_ref(&tracker.url));
+        let peer_id: Bytes = vec![0u8; 20].into();
+
+        let first = trackers.announce(peer_id.clone(), vec![1u8; 20].into()).await;
+        let second = trackers.announce(peer_id.clone(), vec![1u8; 20].into()).await;
+        assert_eq!(first, second);
+        assert_eq!(tracker.announces.load(Ordering::SeqCst), 1);
+
+        // A second torrent on the same tracker reuses the connection id but is not cached.
+        trackers.announce(peer_id, vec![2u8; 20].into()).await;
+        assert_eq!(tracker.announces.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_announce_not_cached_after_interval() {
+        let tracker = MockTracker::spawn(MockOptions {
+            interval: 0,
+            peers: vec![peer(1)],
+            ..MockOptions::default()
+        })
+        .await;
+        let trackers = Trackers::new(std::slice::from_ref(&tracker.url));
+        let peer_id: Bytes = vec![0u8; 20].into();
+
+        trackers.announce(peer_id.clone(), vec![1u8; 20].into()).await;
+        trackers.announce(peer_id, vec![1u8; 20].into()).await;
+        assert_eq!(tracker.announces.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_hostname_resolved_once() {
+        use crate::resolver::BlockingLookup;
+
+        let tracker = MockTracker::spawn(MockOptions {
+            interval: 0,
+            peers: vec![peer(1)],
+            ..MockOptions::default()
+        })
+        .await;
+        let target = SocketAddr::new(
+            tracker.url.host_str().unwrap().parse().unwrap(),
+            tracker.url.port().unwrap(),
+        );
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&lookups);
+        let lookup = BlockingLookup(move |host: &str, port: u16| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            assert_eq!((host, port), ("tracker.test", target.port()));
+            Ok(vec![target])
+        });
+        let resolver = Arc::new(Resolver::new(Arc::new(lookup)));
+        let url = Url::parse(&format!("udp://tracker.test:{}/announce", target.port())).unwrap();
+        let trackers = Trackers::with_resolver(&[url], resolver);
+        let peer_id: Bytes = vec![0u8; 20].into();
+
+        trackers.announce(peer_id.clone(), vec![1u8; 20].into()).await;
+        trackers.announce(peer_id, vec![1u8; 20].into()).await;
+        assert_eq!(tracker.announces.load(Ordering::SeqCst), 2);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peers_flow_before_dead_trackers_fail() {
+        use crate::resolver::BlockingLookup;
+
+        let tracker = MockTracker::spawn(MockOptions {
+            peers: vec![peer(1)],
+            ..MockOptions::default()
+        })
+        .await;
+        let lookup = BlockingLookup(|_: &str, _: u16| {
+            std::thread::sleep(Duration::from_millis(300));
+            Err(std::io::ErrorKind::NotFound.into())
+        });
+        let resolver = Arc::new(Resolver::new(Arc::new(lookup)));
+        let urls = [
+            Url::parse("udp://coppersurfer.test:6969/announce").unwrap(),
+            tracker.url.clone(),
+            Url::parse("udp://leechers-paradise.test:6969/announce").unwrap(),
+        ];
+        let trackers = Trackers::with_resolver(&urls, resolver);
+        let peer_id: Bytes = vec![0u8; 20].into();
+        let mut announces = trackers.announce_stream(peer_id, vec![1u8; 20].into());
+
+        assert_eq!(announces.next().await.unwrap().peers, vec![peer(1)]);
+        let states = trackers.states();
+        assert_eq!(states[0].1, TrackerStatus::Resolving);
+        assert_eq!(states[1].1, TrackerStatus::Connected);
+        assert_eq!(states[2].1, TrackerStatus::Resolving);
+
+        assert_eq!(announces.next().await, None);
+        for (url, status) in trackers.states() {
+            if url != tracker.url {
+                assert!(matches!(status, TrackerStatus::Failed(TrackerFailure::Dns(_))));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce_until_stops_slow_trackers_once_enough() {
+        let fast = MockTracker::spawn(MockOptions {
+            peers: (1..=5).map(peer).collect(),
+            ..MockOptions::default()
+        })
+        .await;
+        let mut urls = vec![fast.url.clone()];
+        for port in 100..105 {
+            let slow = MockTracker::spawn(MockOptions {
+                peers: vec![peer(port)],
+                delay: Duration::from_secs(2),
+                ..MockOptions::default()
+            })
+            .await;
+            urls.push(slow.url);
+        }
+        let trackers = Trackers::new(&urls);
+        let started = Instant::now();
+        let announces = trackers
+            .announce_until(vec![0u8; 20].into(), vec![1u8; 20].into(), 5)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(announces.len(), 1);
+        assert_eq!(announces[0].peers, (1..=5).map(peer).collect::<Vec<_>>());
+        assert!(announces[0].quality > 0.0 && announces[0].quality < 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_announce_concurrency_is_bounded() {
+        let mut urls = Vec::new();
+        for port in 1..=4 {
+            let slow = MockTracker::spawn(MockOptions {
+                peers: vec![peer(port)],
+                delay: Duration::from_millis(200),
+                ..MockOptions::default()
+            })
+            .await;
+            urls.push(slow.url);
+        }
+        let mut trackers = Trackers::new(&urls);
+        trackers.announce_slots = Semaphore::new(2);
+        let started = Instant::now();
+        let peers = trackers.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await;
+        assert_eq!(peers.len(), 4);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_refused_tracker_is_unreachable() {
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("udp://{}/announce", closed.local_addr().unwrap())).unwrap();
+        drop(closed);
+        let trackers = Trackers::new(&[url]);
+        let results = trackers.scrape(vec![1u8; 20].into()).await;
+        assert!(results[0].1.is_err());
+        assert!(matches!(
+            trackers.states()[0].1,
+            TrackerStatus::Failed(TrackerFailure::Unreachable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scrape_swarm_verdict() {
+        let dead = ScrapeStats {
+            seeders: 0,
+            completed: 4,
+            leechers: 2,
+        };
+        let alive = ScrapeStats {
+            seeders: 5,
+            completed: 40,
+            leechers: 7,
+        };
+        let first = MockTracker::spawn(MockOptions {
+            stats: dead,
+            ..MockOptions::default()
+        })
+        .await;
+        let second = MockTracker::spawn(MockOptions {
+            stats: alive,
+            ..MockOptions::default()
+        })
+        .await;
+        let info_hash: Bytes = vec![1u8; 20].into();
+
+        let trackers = Trackers::new(&[first.url.clone(), second.url.clone()]);
+        let results = trackers.scrape(info_hash.clone()).await;
+        assert_eq!(results.len(), 2);
+        for (url, result) in results.iter() {
+            let expected = if *url == first.url { dead } else { alive };
+            assert_eq!(*result.as_ref().unwrap(), expected);
+        }
+        assert!(!swarm_has_no_seeders(&results));
+
+        let trackers = Trackers::new(std::slice::from_ref(&first.url));
+        let results = trackers.scrape(info_hash).await;
+        assert!(swarm_has_no_seeders(&results));
+    }
+
+    #[tokio::test]
+    async fn test_add_tracker_at_runtime() {
+        let tracker = MockTracker::spawn(MockOptions {
+            peers: vec![peer(1)],
+            ..MockOptions::default()
+        })
+        .await;
+        let mut trackers = Trackers::new(&[]);
+        assert!(trackers.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await.is_empty());
+
+        assert!(trackers.add(tracker.url.clone()));
+        assert!(!trackers.add(tracker.url.clone()));
+        let peers = trackers.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await;
+        assert_eq!(peers, vec![peer(1)]);
+    }
+
+    #[test]
+    fn test_metrics_ranking_follows_announce_results() {
+        let mut slow = TrackerMetrics {
+            connect_rtt: Duration::from_millis(900),
+            ..TrackerMetrics::default()
+        };
+        let mut fast = TrackerMetrics {
+            connect_rtt: Duration::from_millis(30),
+            ..TrackerMetrics::default()
+        };
+        assert!(fast.score() > slow.score());
+
+        // The slow tracker keeps finding new peers while the fast one only repeats known ones.
+        for _ in 0..5 {
+            slow.record_announce(Duration::from_millis(900), 320, 50);
+            fast.record_announce(Duration::from_millis(30), 20, 0);
+        }
+        assert!(slow.score() > fast.score());
+        assert_eq!(slow.peer_yield, 50.0);
+
+        // Failures drag a tracker back down.
+        for _ in 0..20 {
+            slow.record_failure();
+        }
+        assert!(fast.score() > slow.score());
+    }
+
+    #[tokio::test]
+    async fn test_announce_stream_ranks_when_polled() {
+        let first = MockTracker::spawn(MockOptions {
+            peers: vec![peer(1)],
+            delay: Duration::from_millis(50),
+            ..MockOptions::default()
+        })
+        .await;
+        let second = MockTracker::spawn(MockOptions {
+            peers: vec![peer(2)],
+            delay: Duration::from_millis(50),
+            ..MockOptions::default()
+        })
+        .await;
+        let mut trackers = Trackers::new(&[first.url.clone(), second.url.clone()]);
+        trackers.announce_slots = Semaphore::new(1);
+        trackers.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await;
+        let fail = |index: usize| {
+            let conn = trackers.trackers[index].connection.get().unwrap();
+            for _ in 0..20 {
+                conn.metrics.lock().unwrap().record_failure();
+            }
+        };
+        fail(1);
+        assert_eq!(trackers.ranked()[0].url, first.url);
+
+        let mut announces = trackers.announce_stream(vec![0u8; 20].into(), vec![1u8; 20].into());
+        fail(0);
+        fail(0);
+        assert_eq!(announces.next().await.unwrap().peers, vec![peer(2)]);
+        assert_eq!(announces.next().await.unwrap().peers, vec![peer(1)]);
+    }
+
+    #[test]
+    fn test_metrics_decay_towards_recent_samples() {
+        let mut metrics = TrackerMetrics::default();
+        metrics.record_announce(Duration::from_millis(100), 100, 10);
+        assert_eq!(metrics.announce_rtt, Some(Duration::from_millis(100)));
+        for _ in 0..20 {
+            metrics.record_announce(Duration::from_millis(500), 100, 0);
+        }
+        let rtt = metrics.announce_rtt.unwrap();
+        assert!(rtt > Duration::from_millis(490) && rtt <= Duration::from_millis(500));
+        assert!(metrics.peer_yield < 0.01);
+    }
+
+    #[tokio::test]
+    async fn test_status_reports_peer_yield() {
+        let first = MockTracker::spawn(MockOptions {
+            peers: vec![peer(1), peer(2)],
+            ..MockOptions::default()
+        })
+        .await;
+        let second = MockTracker::spawn(MockOptions {
+            peers: vec![peer(2), peer(3)],
+            ..MockOptions::default()
+        })
+        .await;
+        let trackers = Trackers::new(&[first.url.clone(), second.url.clone()]);
+        let peers = trackers.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await;
+        assert_eq!(peers.len(), 3);
+
+        let status = trackers.status();
+        assert_eq!(status.len(), 2);
+        let total_yield = status.iter().map(|(_, m)| m.peer_yield).sum::<f64>();
+        assert_eq!(total_yield, 3.0);
+        assert!(status.iter().all(|(_, m)| m.announces == 1 && m.response_bytes == 32.0));
+        assert!(status[0].1.score() >= status[1].1.score());
+    }
+
+    /// Header of an announce response for a swarm of `seeders`, followed by `peers` entries.
+    fn announce_datagram(transaction_id: u32, seeders: u32, peers: u16) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        for word in [1u32, transaction_id, 1800, 0, seeders] {
+            datagram.extend_from_slice(&word.to_be_bytes());
+        }
+        for port in 1..=peers {
+            datagram.extend_from_slice(&[10, 0, 0, 1]);
+            datagram.extend_from_slice(&port.to_be_bytes());
+        }
+        datagram
+    }
+
+    #[test]
+    fn test_truncated_announce_keeps_complete_entries() {
+        let mut datagram = announce_datagram(7, 200, 3);
+        datagram.truncate(datagram.len() - 4);
+        let response = AnnounceResponse::from_bytes(&datagram, false).unwrap();
+        assert_eq!(response.peers, vec![peer(1), peer(2)]);
+        assert_eq!(response.fragment, 2);
+        assert_eq!(response.seeders, 200);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_announce_is_followed_up_with_smaller_num_want() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        let num_wants = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&num_wants);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                let transaction_id = BigEndian::read_u32(&buf[12..16]);
+                let response = if n == CONNECT_REQUEST_SIZE {
+                    let mut response = vec![0u8; 8];
+                    BigEndian::write_u32(&mut response[4..8], transaction_id);
+                    response.extend_from_slice(&0x1234i64.to_be_bytes());
+                    response
+                } else {
+                    let num_want = BigEndian::read_i32(&buf[92..96]);
+                    seen.lock().unwrap().push(num_want);
+                    if num_want == NUM_WANT_DEFAULT {
+                        // Cut off part way through the ninth entry.
+                        let mut response = announce_datagram(transaction_id, 200, 9);
+                        response.truncate(response.len() - 3);
+                        response
+                    } else {
+                        announce_datagram(transaction_id, 200, num_want as u16)
+                    }
+                };
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        let mut trackers = Trackers::new(&[url]);
+        trackers.truncated_follow_up = Duration::from_millis(10);
+        let announces = trackers
+            .announce_stream(vec![0u8; 20].into(), vec![1u8; 20].into())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(announces.len(), 2);
+        assert_eq!(announces[0].peers.len(), 8);
+        assert_eq!(announces[1].peers.len(), 4);
+        assert_eq!(*num_wants.lock().unwrap(), vec![NUM_WANT_DEFAULT, 4]);
+        assert_eq!(trackers.status()[0].1.truncated, 1);
+    }
+
+    #[test]
+    fn test_hostile_announce_responses_never_panic() {
+        use crate::testutil::{arbitrary_bytes, corrupt};
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut valid = Vec::new();
+        for word in [1u32, 7, 1800, 3, 4] {
+            valid.extend_from_slice(&word.to_be_bytes());
+        }
+        valid.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe1]);
+        let response = AnnounceResponse::from_bytes(&valid, false).unwrap();
+        assert_eq!(response.peers, vec![peer(6881), SocketAddr::from(([10, 0, 0, 2], 6881))]);
+        assert_eq!(response.fragment, 0);
+        assert!(AnnounceResponse::from_bytes(&valid[..12], false).is_err());
+
+        let mut rng = StdRng::seed_from_u64(1200);
+        for _ in 0..5000 {
+            let _ = AnnounceResponse::from_bytes(&corrupt(&mut rng, &valid), false);
+            let _ = AnnounceResponse::from_bytes(&arbitrary_bytes(&mut rng, 100), true);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker_socket_addr_forms() {
+        use crate::resolver::BlockingLookup;
+
+        let v4 = SocketAddr::from(([10, 0, 0, 1], 6969));
+        let v6 = "[2001:db8::1]:6969".parse::<SocketAddr>().unwrap();
+        let lookup = BlockingLookup(move |host: &str, _: u16| match host {
+            "dual.test" => Ok(vec![v6, v4]),
+            "v6only.test" => Ok(vec![v6]),
+            _ => Err(std::io::ErrorKind::NotFound.into()),
+        });
+        let resolver = Resolver::new(Arc::new(lookup));
+        let resolve = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            let resolver = &resolver;
+            async move { tracker_socket_addr(&url, resolver).await }
+        };
+        assert_eq!(resolve("udp://10.0.0.1:6969/announce").await.unwrap(), v4);
+        assert_eq!(resolve("udp://[2001:db8::1]:6969/announce").await.unwrap(), v6);
+        assert_eq!(resolve("http://[2001:db8::1]/announce").await.unwrap().port(), 80);
+        assert_eq!(resolve("udp://dual.test:6969/announce").await.unwrap(), v4);
+        assert_eq!(resolve("udp://v6only.test:6969/announce").await.unwrap(), v6);
+        assert!(resolve("udp://[2001:db8::1]/announce").await.is_err());
+        assert!(resolve("udp://nxdomain.test:6969/announce").await.is_err());
+        assert!(local_bind_addr(v6).is_ipv6() && local_bind_addr(v4).is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn test_announce_over_ipv6_literal() {
+        let peers = vec![
+            "[2001:db8::7]:6881".parse().unwrap(),
+            "[2001:db8::8]:51413".parse().unwrap(),
+        ];
+        let tracker = MockTracker::spawn(MockOptions {
+            bind: "[::1]:0",
+            peers: peers.clone(),
+            ..MockOptions::default()
+        })
+        .await;
+        assert!(tracker.url.as_str().starts_with("udp://[::1]:"));
+        let trackers = Trackers::new(std::slice::from_ref(&tracker.url));
+        let announced = trackers.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await;
+        assert_eq!(announced.into_iter().collect::<HashSet<_>>(), peers.into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn test_history_ranks_working_tracker_and_delays_dead_one() {
+        let working = MockTracker::spawn(MockOptions {
+            peers: vec![peer(1)],
+            ..MockOptions::default()
+        })
+        .await;
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dead = Url::parse(&format!("udp://{}/announce", closed.local_addr().unwrap())).unwrap();
+        drop(closed);
+        let urls = [dead.clone(), working.url.clone()];
+        let path = std::env::temp_dir().join(format!("magdl-trackers-{}", rand::random::<u64>()));
+
+        let first = Trackers::new(&urls);
+        first.announce(vec![0u8; 20].into(), vec![1u8; 20].into()).await;
+        first.updated_history(SystemTime::now()).save(&path).unwrap();
+
+        let history = TrackerHistory::load(&path, SystemTime::now()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut second = Trackers::with_history(&[], Arc::new(Resolver::system()), history);
+        second.cooling_delay = Duration::from_millis(300);
+        for url in &urls {
+            second.add(url.clone());
+        }
+        assert_eq!(second.ranked()[0].url, working.url);
+
+        let started = Instant::now();
+        let mut announces = second.announce_stream(vec![0u8; 20].into(), vec![1u8; 20].into());
+        assert_eq!(announces.next().await.unwrap().peers, vec![peer(1)]);
+        assert_eq!(second.states()[0].1, TrackerStatus::Resolving);
+        assert_eq!(announces.next().await, None);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(matches!(
+            second.states()[0].1,
+            TrackerStatus::Failed(TrackerFailure::Unreachable(_))
+        ));
+        assert_eq!(working.announces.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{collections::BTreeMap, fmt::Display, net::SocketAddr};
+
+use crate::peer_capabilities::Capabilities;
+
+/// Protocol deviations by a remote peer. The codes are stable so that tooling can match on
+/// them in a `--report` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Violation {
+    /// Handshake protocol string is not "BitTorrent protocol".
+    BadProtocolString,
+    UnknownMessageId,
+    /// Length prefix beyond `MAX_MESSAGE_LEN`.
+    MessageTooLong,
+    /// Piece without a Request for it. Every Piece is one, as no Requests are sent yet.
+    UnsolicitedPiece,
+    /// Have payload is not exactly four bytes.
+    BadHaveLength,
+    HaveOutOfRange,
+    /// Bitfield is not `ceil(pieces / 8)` bytes.
+    BitfieldLength,
+    BitfieldSpareBits,
+    /// Bitfield sent after other messages. Tolerated unless strict.
+    LateBitfield,
+}
+impl Violation {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Violation::BadProtocolString => "bad-pstr",
+            Violation::UnknownMessageId => "unknown-message-id",
+            Violation::MessageTooLong => "message-too-long",
+            Violation::UnsolicitedPiece => "unsolicited-piece",
+            Violation::BadHaveLength => "bad-have-length",
+            Violation::HaveOutOfRange => "have-out-of-range",
+            Violation::BitfieldLength => "bitfield-length",
+            Violation::BitfieldSpareBits => "bitfield-spare-bits",
+            Violation::LateBitfield => "late-bitfield",
+        }
+    }
+    /// Finds the violation an error was raised for, including one carried by an io error.
+    pub fn find(error: &anyhow::Error) -> Option<Self> {
+        error.chain().find_map(|e| {
+            e.downcast_ref::<Violation>().copied().or_else(|| {
+                e.downcast_ref::<std::io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .and_then(|e| e.downcast_ref::<Violation>())
+                    .copied()
+            })
+        })
+    }
+    /// An error for this violation with `detail` as its message.
+    pub fn error(self, detail: String) -> anyhow::Error {
+        anyhow::Error::new(self).context(detail)
+    }
+}
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "protocol violation {}", self.code())
+    }
+}
+impl std::error::Error for Violation {}
+
+/// Violations seen per peer in strict mode.
+#[derive(Debug, Default)]
+pub struct ViolationReport {
+    peers: BTreeMap<SocketAddr, BTreeMap<Violation, usize>>,
+    /// Last known capabilities of each peer that has violations. A peer is described as it
+    /// disconnects, before the violation that ended it is recorded, so `disconnected` drops
+    /// the description again if no violation follows.
+    capabilities: BTreeMap<SocketAddr, Capabilities>,
+}
+impl ViolationReport {
+    pub fn describe(&mut self, addr: SocketAddr, capabilities: Capabilities) {
+        self.capabilities.insert(addr, capabilities);
+    }
+    /// The peer's connection is over and its violations, if any, are recorded.
+    pub fn disconnected(&mut self, addr: SocketAddr) {
+        if !self.peers.contains_key(&addr) {
+            self.capabilities.remove(&addr);
+        }
+    }
+    pub fn record(&mut self, addr: SocketAddr, violation: Violation) {
+        *self
+            .peers
+            .entry(addr)
+            .or_default()
+            .entry(violation)
+            .or_insert(0) += 1;
+    }
+    #[cfg(test)]
+    pub fn count(&self, addr: SocketAddr, violation: Violation) -> usize {
+        self.peers
+            .get(&addr)
+            .and_then(|violations| violations.get(&violation))
+            .copied()
+            .unwrap_or(0)
+    }
+    /// `{"peers":[{"addr":"1.2.3.4:6881","capabilities":{...},"violations":{"late-bitfield":1}}]}`,
+    /// without `capabilities` for a peer that was never described.
+    pub fn to_json(&self) -> String {
+        let peers = self
+            .peers
+            .iter()
+            .map(|(addr, violations)| {
+                let violations = violations
+                    .iter()
+                    .map(|(violation, count)| format!("\"{}\":{}", violation.code(), count))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let capabilities = match self.capabilities.get(addr) {
+                    Some(capabilities) => format!(",\"capabilities\":{}", capabilities.to_json()),
+                    None => String::new(),
+                };
+                format!(
+                    "{{\"addr\":\"{}\"{},\"violations\":{{{}}}}}",
+                    addr, capabilities, violations
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{{\"peers\":[{}]}}", peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_through_context_and_io() {
+        let error = Violation::LateBitfield.error("Bitfield after 3 messages".to_string());
+        assert_eq!(Violation::find(&error), Some(Violation::LateBitfield));
+        assert_eq!(error.to_string(), "Bitfield after 3 messages");
+
+        let io = std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            Violation::BadProtocolString,
+        );
+        assert_eq!(
+            Violation::find(&io.into()),
+            Some(Violation::BadProtocolString)
+        );
+        assert_eq!(Violation::find(&anyhow::anyhow!("other")), None);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let mut report = ViolationReport::default();
+        let a = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let b = SocketAddr::from(([10, 0, 0, 2], 6881));
+        report.record(b, Violation::UnknownMessageId);
+        report.record(a, Violation::LateBitfield);
+        report.record(a, Violation::LateBitfield);
+        report.record(a, Violation::BadHaveLength);
+        assert_eq!(report.count(a, Violation::LateBitfield), 2);
+        // Described peers without violations are left out, and forgotten once they disconnect.
+        let clean = SocketAddr::from(([10, 0, 0, 3], 6881));
+        report.describe(clean, Capabilities::default());
+        report.describe(a, Capabilities::default());
+        report.disconnected(clean);
+        report.disconnected(a);
+        assert_eq!(report.capabilities.keys().collect::<Vec<_>>(), vec![&a]);
+        report.capabilities.clear();
+        assert_eq!(
+            report.to_json(),
+            "{\"peers\":[\
+             {\"addr\":\"10.0.0.1:6881\",\"violations\":{\"bad-have-length\":1,\"late-bitfield\":2}},\
+             {\"addr\":\"10.0.0.2:6881\",\"violations\":{\"unknown-message-id\":1}}]}"
+        );
+    }
+}
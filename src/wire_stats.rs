@@ -0,0 +1,211 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::{peer_codec::PeerFrame, peer_message::PeerMessageType, peer_trace::Direction};
+
+/// Bytes of a Piece message that are not block data: the index and begin fields.
+const PIECE_HEADER_BYTES: usize = 8;
+
+/// Bytes in one direction, split into block data and everything else.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WireTotals {
+    /// Block data carried by Piece messages.
+    pub payload: u64,
+    /// Framing, handshakes, keep-alives and every non-Piece message.
+    pub overhead: u64,
+}
+impl WireTotals {
+    pub fn total(&self) -> u64 {
+        self.payload + self.overhead
+    }
+    pub fn overhead_percent(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => self.overhead as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    payload: AtomicU64,
+    overhead: AtomicU64,
+}
+impl Counters {
+    fn add(&self, payload: u64, overhead: u64) {
+        self.payload.fetch_add(payload, Ordering::Relaxed);
+        self.overhead.fetch_add(overhead, Ordering::Relaxed);
+    }
+    fn totals(&self) -> WireTotals {
+        WireTotals {
+            payload: self.payload.load(Ordering::Relaxed),
+            overhead: self.overhead.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Wire byte counters, updated by the codec as frames are encoded and decoded. A peer's
+/// counters also add into their parent, the session-wide counters.
+#[derive(Debug, Default)]
+pub struct WireStats {
+    parent: Option<Arc<WireStats>>,
+    inbound: Counters,
+    outbound: Counters,
+}
+impl WireStats {
+    pub fn child(parent: &Arc<WireStats>) -> Self {
+        Self {
+            parent: Some(Arc::clone(parent)),
+            ..Self::default()
+        }
+    }
+    pub fn record(&self, direction: Direction, frame: &PeerFrame) {
+        let (payload, overhead) = split(frame);
+        let counters = match direction {
+            Direction::Inbound => &self.inbound,
+            Direction::Outbound => &self.outbound,
+        };
+        counters.add(payload, overhead);
+        if let Some(parent) = &self.parent {
+            parent.record(direction, frame);
+        }
+    }
+    pub fn inbound(&self) -> WireTotals {
+        self.inbound.totals()
+    }
+    pub fn outbound(&self) -> WireTotals {
+        self.outbound.totals()
+    }
+}
+
+/// Payload and overhead bytes of one frame as it appears on the wire.
+fn split(frame: &PeerFrame) -> (u64, u64) {
+    let wire_len = frame.wire_len() as u64;
+    let payload = match frame {
+        PeerFrame::Data(data) if data.message_id == PeerMessageType::Piece.raw_value() => {
+            data.payload.len().saturating_sub(PIECE_HEADER_BYTES) as u64
+        }
+        _ => 0,
+    };
+    (payload, wire_len - payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_codec::{Data, Handshake, PeerCodec, BITTORRENT_PROTOCOL};
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio_util::codec::Framed;
+
+    /// Passes IO through while counting the bytes that cross it.
+    struct CountingStream<S> {
+        inner: S,
+        read: Arc<AtomicU64>,
+        written: Arc<AtomicU64>,
+    }
+    impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let before = buf.filled().len();
+            let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+            let read = (buf.filled().len() - before) as u64;
+            self.read.fetch_add(read, Ordering::SeqCst);
+            result
+        }
+    }
+    impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = result {
+                self.written.fetch_add(n as u64, Ordering::SeqCst);
+            }
+            result
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    fn frames() -> Vec<PeerFrame> {
+        let mut piece = vec![0, 0, 0, 3, 0, 0, 0x40, 0];
+        piece.extend_from_slice(&[7u8; 0x4000]);
+        vec![
+            PeerFrame::Handshake(Handshake {
+                pstr: BITTORRENT_PROTOCOL.into(),
+                reserved: [0; 8],
+                info_hash: Bytes::from_static(&[1; 20]),
+                peer_id: Bytes::from_static(&[2; 20]),
+            }),
+            PeerFrame::KeepAlive,
+            PeerFrame::Data(Data {
+                message_id: 5,
+                payload: Bytes::from_static(&[0xff, 0x80]),
+            }),
+            PeerFrame::Data(Data {
+                message_id: 7,
+                payload: piece.into(),
+            }),
+            PeerFrame::Data(Data {
+                message_id: 4,
+                payload: Bytes::from_static(&[0, 0, 0, 3]),
+            }),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_counters_match_bytes_on_the_socket() {
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let read = Arc::new(AtomicU64::new(0));
+        let written = Arc::new(AtomicU64::new(0));
+        let counting = CountingStream {
+            inner: ours,
+            read: Arc::clone(&read),
+            written: Arc::clone(&written),
+        };
+        let session = Arc::new(WireStats::default());
+        let peer = Arc::new(WireStats::child(&session));
+        let mut ours = Framed::new(counting, PeerCodec::new().with_stats(Arc::clone(&peer)));
+        let mut theirs = Framed::new(theirs, PeerCodec::new());
+
+        for frame in frames() {
+            ours.send(frame).await.unwrap();
+        }
+        for frame in frames() {
+            theirs.send(frame).await.unwrap();
+        }
+        for _ in 0..frames().len() {
+            theirs.next().await.unwrap().unwrap();
+            ours.next().await.unwrap().unwrap();
+        }
+
+        for stats in [&peer, &session] {
+            assert_eq!(stats.outbound().total(), written.load(Ordering::SeqCst));
+            assert_eq!(stats.inbound().total(), read.load(Ordering::SeqCst));
+            assert_eq!(stats.inbound().payload, 0x4000);
+            assert_eq!(stats.outbound().payload, 0x4000);
+        }
+        // 68 handshake + 4 keep-alive + 7 bitfield + 13 piece header + 9 have.
+        assert_eq!(peer.outbound().overhead, 101);
+    }
+}